@@ -0,0 +1,9 @@
+#![no_main]
+
+use imap_source_fuzz::FetchInput;
+use libfuzzer_sys::fuzz_target;
+
+// Header, text, envelope and modes picked by the fuzzer
+fuzz_target!(|input: FetchInput| {
+    imap_source_fuzz::fill_record(&input);
+});
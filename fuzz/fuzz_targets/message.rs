@@ -0,0 +1,9 @@
+#![no_main]
+
+use imap_source_fuzz::FetchInput;
+use libfuzzer_sys::fuzz_target;
+
+// Raw bytes as the whole message with every mode enabled
+fuzz_target!(|data: &[u8]| {
+    imap_source_fuzz::fill_record(&FetchInput::from_message(data));
+});
@@ -0,0 +1,256 @@
+// Entry points of the fuzz targets into the record pipeline of the connector library.
+
+use arbitrary::Arbitrary;
+use async_imap::types::Fetch;
+use async_std::io::{Read, Write};
+use futures::StreamExt;
+use imap_source::config::ImapConfig;
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const FETCH: &str = "(UID RFC822.SIZE INTERNALDATE RFC822.HEADER RFC822.TEXT RFC822 ENVELOPE)";
+
+// Bit per entry of FetchInput::modes
+const MODES: [&str; 6] = [
+    "mode_bytes",
+    "mode_utf8_lossy",
+    "mode_parser",
+    "mode_normalized",
+    "mode_dkim_auth",
+    "mode_auth_results",
+];
+const TRUSTED_AUTHSERV_IDS_BIT: u8 = 1 << 6;
+const ATTACHMENTS_BIT: u8 = 1 << 7;
+
+#[derive(Arbitrary, Debug)]
+pub struct FetchInput {
+    pub header: Vec<u8>,
+    pub text: Vec<u8>,
+    pub envelope: Option<EnvelopeInput>,
+    pub modes: u8,
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct EnvelopeInput {
+    pub date: Option<Vec<u8>>,
+    pub subject: Option<Vec<u8>>,
+    pub from: Vec<AddressInput>,
+    pub sender: Vec<AddressInput>,
+    pub reply_to: Vec<AddressInput>,
+    pub to: Vec<AddressInput>,
+    pub cc: Vec<AddressInput>,
+    pub bcc: Vec<AddressInput>,
+    pub in_reply_to: Option<Vec<u8>>,
+    pub message_id: Option<Vec<u8>>,
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct AddressInput {
+    pub name: Option<Vec<u8>>,
+    pub adl: Option<Vec<u8>>,
+    pub mailbox: Option<Vec<u8>>,
+    pub host: Option<Vec<u8>>,
+}
+
+impl FetchInput {
+    // The whole message split into header & text the way the server does, every mode enabled
+    pub fn from_message(message: &[u8]) -> Self {
+        let (header, text) = match message.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => message.split_at(end + 4),
+            None => (message, &message[message.len()..]),
+        };
+        Self {
+            header: header.to_vec(),
+            text: text.to_vec(),
+            envelope: None,
+            modes: u8::MAX,
+        }
+    }
+
+    // FETCH attributes as the server would send them - strings as literals so that any bytes go
+    fn attributes(&self) -> Vec<u8> {
+        let mut message = self.header.clone();
+        message.extend(&self.text);
+
+        let mut out = format!(
+            "UID 1 RFC822.SIZE {} INTERNALDATE \"05-Jul-2024 02:26:27 +0000\"",
+            message.len()
+        )
+        .into_bytes();
+        out.extend(b" RFC822.HEADER ");
+        out.extend(literal(&self.header));
+        out.extend(b" RFC822.TEXT ");
+        out.extend(literal(&self.text));
+        out.extend(b" RFC822 ");
+        out.extend(literal(&message));
+        if let Some(envelope) = &self.envelope {
+            out.extend(b" ENVELOPE ");
+            out.extend(envelope.attribute());
+        }
+        out
+    }
+}
+
+impl EnvelopeInput {
+    fn attribute(&self) -> Vec<u8> {
+        let fields = [
+            nstring(&self.date),
+            nstring(&self.subject),
+            address_list(&self.from),
+            address_list(&self.sender),
+            address_list(&self.reply_to),
+            address_list(&self.to),
+            address_list(&self.cc),
+            address_list(&self.bcc),
+            nstring(&self.in_reply_to),
+            nstring(&self.message_id),
+        ];
+        let mut out = b"(".to_vec();
+        out.extend(fields.join(&b' '));
+        out.push(b')');
+        out
+    }
+}
+
+fn address_list(addresses: &[AddressInput]) -> Vec<u8> {
+    if addresses.is_empty() {
+        return b"NIL".to_vec();
+    }
+    let mut out = b"(".to_vec();
+    for address in addresses {
+        let parts = [
+            nstring(&address.name),
+            nstring(&address.adl),
+            nstring(&address.mailbox),
+            nstring(&address.host),
+        ];
+        out.push(b'(');
+        out.extend(parts.join(&b' '));
+        out.push(b')');
+    }
+    out.push(b')');
+    out
+}
+
+fn nstring(value: &Option<Vec<u8>>) -> Vec<u8> {
+    match value {
+        Some(value) => literal(value),
+        None => b"NIL".to_vec(),
+    }
+}
+
+fn literal(value: &[u8]) -> Vec<u8> {
+    let mut out = format!("{{{}}}\r\n", value.len()).into_bytes();
+    out.extend(value);
+    out
+}
+
+// Through fill_record and the JSON conversion - errors are fine, panics are not
+pub fn fill_record(input: &FetchInput) {
+    let item = match fetch(input.attributes()) {
+        Some(item) => item,
+        None => return,
+    };
+
+    let mut config = serde_json::json!({
+        "host": "localhost",
+        "port": "993",
+        "user": "user",
+        "mailbox": "INBOX",
+        "search": "ALL",
+        "fetch": FETCH,
+        "dangerous_cert": false,
+    });
+    for (bit, mode) in MODES.iter().enumerate() {
+        config[*mode] = (input.modes & (1 << bit) != 0).into();
+    }
+    if input.modes & TRUSTED_AUTHSERV_IDS_BIT != 0 {
+        config["trusted_authserv_ids"] = serde_json::json!(["mx.example.org"]);
+        config["dmarc_move"] = serde_json::json!([{"result": "fail", "mailbox": "Quarantine"}]);
+    }
+    if input.modes & ATTACHMENTS_BIT != 0 {
+        config["attachments"] = serde_json::json!({"records": true});
+    }
+    let config: ImapConfig = serde_json::from_value(config).unwrap();
+
+    imap_source::fuzz_fill_record(&config, &item);
+}
+
+// The FETCH attributes as async-imap parses them - served over an in-memory stream
+fn fetch(attributes: Vec<u8>) -> Option<Fetch> {
+    async_std::task::block_on(async {
+        let client = async_imap::Client::new(ScriptedStream::new(attributes));
+        let mut session = client.login("user", "secret").await.ok()?;
+        let mut fetches = session.uid_fetch("1", FETCH).await.ok()?;
+        let item = fetches.next().await?.ok();
+        drop(fetches);
+        item
+    })
+}
+
+// Answers every command OK and UID FETCH with the scripted attributes
+#[derive(Debug)]
+struct ScriptedStream {
+    attributes: Vec<u8>,
+    written: Vec<u8>,
+    responses: Vec<u8>,
+}
+
+impl ScriptedStream {
+    fn new(attributes: Vec<u8>) -> Self {
+        Self {
+            attributes,
+            written: vec![],
+            responses: vec![],
+        }
+    }
+}
+
+impl Read for ScriptedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // Nothing left to say is the end of the connection
+        let this = self.get_mut();
+        let n = buf.len().min(this.responses.len());
+        buf[..n].copy_from_slice(&this.responses[..n]);
+        this.responses.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Write for ScriptedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.written.extend(buf);
+        while let Some(end) = this.written.windows(2).position(|w| w == b"\r\n") {
+            let line: Vec<u8> = this.written.drain(..end + 2).collect();
+            let line = String::from_utf8_lossy(&line).to_string();
+            let (tag, command) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+            if command.to_ascii_uppercase().starts_with("UID FETCH") {
+                this.responses.extend(b"* 1 FETCH (");
+                this.responses.extend(&this.attributes);
+                this.responses.extend(b")\r\n");
+            }
+            this.responses
+                .extend(format!("{} OK completed\r\n", tag.trim()).as_bytes());
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
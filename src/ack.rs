@@ -0,0 +1,110 @@
+use crate::actions::MessageAction;
+use crate::checkpoint::Checkpoints;
+use crate::event::RecordAck;
+use anyhow::Result;
+use async_std::channel::{self, Receiver, Sender};
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::collections::HashMap;
+
+// Tracks the records handed to the producer until Fluvio has acknowledged them.
+// Lives across reconnects so that acknowledgements of records sent by a previous session still count.
+#[derive(Debug)]
+pub(crate) struct AckTracker {
+    tx: Sender<RecordAck>,
+    rx: Receiver<RecordAck>,
+    // Produced but not yet acknowledged - mailbox -> uid -> (records left, actions)
+    pending: HashMap<String, HashMap<u32, (usize, Vec<MessageAction>)>>,
+    // Acknowledged with actions still to carry out once the mailbox is selected
+    acked: HashMap<String, Vec<(u32, Vec<MessageAction>)>>,
+}
+
+impl AckTracker {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = channel::unbounded();
+        Self {
+            tx,
+            rx,
+            pending: HashMap::new(),
+            acked: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn sender(&self) -> Sender<RecordAck> {
+        self.tx.clone()
+    }
+
+    // A message may be produced as several records - e.g. with its attachments - all of them need acknowledging
+    pub(crate) fn produced(
+        &mut self,
+        mailbox: &str,
+        uid: u32,
+        records: usize,
+        actions: Vec<MessageAction>,
+    ) {
+        self.pending
+            .entry(mailbox.to_string())
+            .or_default()
+            .insert(uid, (records, actions));
+    }
+
+    // Not produced at all - the actions are due straight away
+    pub(crate) fn skipped(&mut self, mailbox: &str, uid: u32, actions: Vec<MessageAction>) {
+        if !actions.is_empty() {
+            self.acked
+                .entry(mailbox.to_string())
+                .or_default()
+                .push((uid, actions));
+        }
+    }
+
+    // Wait until every record of the mailbox has been acknowledged, advancing the checkpoints on the way
+    pub(crate) async fn wait_for(
+        &mut self,
+        mailbox: &str,
+        checkpoints: &mut Checkpoints,
+    ) -> Result<()> {
+        while self.pending.get(mailbox).is_some_and(|p| !p.is_empty()) {
+            let ack = self.rx.recv().await?;
+            trace!("Acknowledged {} UID {}", &ack.mailbox, ack.uid);
+
+            let pending = match self.pending.get_mut(&ack.mailbox) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            if let Some((records, _)) = pending.get_mut(&ack.uid) {
+                *records = records.saturating_sub(1);
+                if *records > 0 {
+                    continue;
+                }
+            }
+            if let Some((_, actions)) = pending.remove(&ack.uid) {
+                checkpoints.advance(&ack.mailbox, ack.uid);
+                if !actions.is_empty() {
+                    self.acked
+                        .entry(ack.mailbox)
+                        .or_default()
+                        .push((ack.uid, actions));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Acknowledged messages of the mailbox whose actions are due - kept until carried out
+    pub(crate) fn acked(&self, mailbox: &str) -> Vec<(u32, Vec<MessageAction>)> {
+        self.acked.get(mailbox).cloned().unwrap_or_default()
+    }
+
+    // The actions of the mailbox were carried out - a failure leaves them due for the next session
+    pub(crate) fn actions_done(&mut self, mailbox: &str) {
+        self.acked.remove(mailbox);
+    }
+
+    // Mailboxes with actions due - they need syncing even when nothing new has arrived
+    pub(crate) fn mailboxes_with_acked(&self) -> Vec<String> {
+        self.acked.keys().cloned().collect()
+    }
+}
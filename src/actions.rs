@@ -0,0 +1,273 @@
+use crate::config::{ActionConfig, ActionFilter, ImapConfig};
+use crate::error::ImapError;
+use crate::event::ImapEvent;
+use anyhow::Result;
+use async_imap::Session as ImapSession;
+use futures::StreamExt;
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use async_std::io::{Read, Write};
+use core::fmt;
+use std::collections::BTreeMap;
+
+// Post-processing of a message - only carried out once its record has been acknowledged
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MessageAction {
+    AddFlags(Vec<String>),
+    RemoveFlags(Vec<String>),
+    Copy(String),
+    Move(String),
+    Delete,
+}
+
+impl From<&ActionConfig> for MessageAction {
+    fn from(action: &ActionConfig) -> Self {
+        match action {
+            ActionConfig::AddFlags { flags } => MessageAction::AddFlags(flags.clone()),
+            ActionConfig::RemoveFlags { flags } => MessageAction::RemoveFlags(flags.clone()),
+            ActionConfig::Copy { mailbox } => MessageAction::Copy(mailbox.clone()),
+            ActionConfig::Move { mailbox } => MessageAction::Move(mailbox.clone()),
+            ActionConfig::Delete => MessageAction::Delete,
+        }
+    }
+}
+
+// Actions due for the message - the DKIM move followed by the matching on_produced actions
+pub(crate) fn resolve_actions(config: &ImapConfig, rec: &ImapEvent) -> Vec<MessageAction> {
+    let mut actions = vec![];
+    if let Some(ref move_to) = rec.moved_to {
+        actions.push(MessageAction::Move(move_to.clone()));
+    }
+    for on_produced in config.on_produced.iter() {
+        if filter_matches(&on_produced.when, rec) {
+            actions.push((&on_produced.action).into());
+        }
+    }
+    actions
+}
+
+fn filter_matches(filter: &ActionFilter, rec: &ImapEvent) -> bool {
+    if let Some(mailbox) = &filter.mailbox {
+        if *mailbox != rec.mailbox {
+            return false;
+        }
+    }
+    if let Some(dkim_authenticated) = filter.dkim_authenticated {
+        if rec.dkim_authenticated != Some(dkim_authenticated) {
+            return false;
+        }
+    }
+    if let Some(from_domain) = &filter.from_domain {
+        let from_host = rec
+            .envelope
+            .as_ref()
+            .and_then(|e| e.from.as_ref())
+            .and_then(|from| from.first())
+            .and_then(|from| from.host.as_ref());
+        match from_host {
+            Some(host) if host.eq_ignore_ascii_case(from_domain) => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+// Carry out the actions of the acknowledged messages - the mailbox must be selected
+pub(crate) async fn apply_actions<T>(
+    fetch_session: &mut ImapSession<T>,
+    mailbox: &str,
+    acked: Vec<(u32, Vec<MessageAction>)>,
+) -> Result<()>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    // Grouped so that each distinct action is a single command over a UID set
+    let mut add_flags: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut remove_flags: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut copies: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut moves: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    let mut deletes: Vec<u32> = vec![];
+
+    for (uid, actions) in acked {
+        for action in settle_terminal(uid, actions) {
+            match action {
+                MessageAction::AddFlags(flags) => {
+                    add_flags.entry(flags.join(" ")).or_default().push(uid)
+                }
+                MessageAction::RemoveFlags(flags) => {
+                    remove_flags.entry(flags.join(" ")).or_default().push(uid)
+                }
+                MessageAction::Copy(copy_to) => copies.entry(copy_to).or_default().push(uid),
+                MessageAction::Move(move_to) => moves.entry(move_to).or_default().push(uid),
+                MessageAction::Delete => deletes.push(uid),
+            }
+        }
+    }
+
+    // Flags & copies first - the messages are gone from the mailbox after moving / deleting
+    for (flags, uids) in add_flags {
+        let uid_set = crate::imap_util::uid_set(&uids);
+        debug!("Adding flags ({}) to {} in {}", &flags, &uid_set, mailbox);
+        store(
+            fetch_session,
+            mailbox,
+            &uid_set,
+            &format!("+FLAGS.SILENT ({})", flags),
+        )
+        .await?;
+    }
+
+    for (flags, uids) in remove_flags {
+        let uid_set = crate::imap_util::uid_set(&uids);
+        debug!(
+            "Removing flags ({}) from {} in {}",
+            &flags, &uid_set, mailbox
+        );
+        store(
+            fetch_session,
+            mailbox,
+            &uid_set,
+            &format!("-FLAGS.SILENT ({})", flags),
+        )
+        .await?;
+    }
+
+    for (copy_to, uids) in copies {
+        let uid_set = crate::imap_util::uid_set(&uids);
+        info!("Copying {} from {} to {}", &uid_set, mailbox, &copy_to);
+        fetch_session
+            .uid_copy(&uid_set, &copy_to)
+            .await
+            .map_err(|source| ImapError::Copy {
+                mailbox: mailbox.to_string(),
+                uids: uid_set.clone(),
+                destination: copy_to.to_string(),
+                source,
+            })?;
+    }
+
+    for (move_to, uids) in moves {
+        let uid_set = crate::imap_util::uid_set(&uids);
+        info!("Moving {} from {} to {}", &uid_set, mailbox, &move_to);
+        fetch_session
+            .uid_mv(&uid_set, &move_to)
+            .await
+            .map_err(|source| ImapError::Move {
+                mailbox: mailbox.to_string(),
+                uids: uid_set.clone(),
+                destination: move_to.to_string(),
+                source,
+            })?;
+    }
+
+    if !deletes.is_empty() {
+        let uid_set = crate::imap_util::uid_set(&deletes);
+        info!("Deleting {} from {}", &uid_set, mailbox);
+        store(
+            fetch_session,
+            mailbox,
+            &uid_set,
+            "+FLAGS.SILENT (\\Deleted)",
+        )
+        .await?;
+
+        // UID EXPUNGE (UIDPLUS) leaves other messages marked \Deleted alone
+        let expunge_error = |source| ImapError::Expunge {
+            mailbox: mailbox.to_string(),
+            uids: uid_set.clone(),
+            source,
+        };
+        let expunged: Vec<_> = fetch_session
+            .uid_expunge(&uid_set)
+            .await
+            .map_err(expunge_error)?
+            .collect()
+            .await;
+        for uid in expunged {
+            uid.map_err(expunge_error)?;
+        }
+    }
+
+    Ok(())
+}
+
+// A message can only end up in one place - the first move or delete wins: the DKIM / DMARC move,
+// then on_produced and then the rules in their order. Duplicates are dropped.
+pub(crate) fn settle_terminal(uid: u32, actions: Vec<MessageAction>) -> Vec<MessageAction> {
+    let mut settled: Vec<MessageAction> = vec![];
+    let mut terminal: Option<MessageAction> = None;
+    for action in actions {
+        if settled.contains(&action) {
+            continue;
+        }
+        if matches!(action, MessageAction::Move(_) | MessageAction::Delete) {
+            if let Some(terminal) = &terminal {
+                warn!(
+                    "Ignoring {:?} of UID {} conflicting with {:?}",
+                    &action, uid, terminal
+                );
+                continue;
+            }
+            terminal = Some(action.clone());
+        }
+        settled.push(action);
+    }
+    settled
+}
+
+async fn store<T>(
+    fetch_session: &mut ImapSession<T>,
+    mailbox: &str,
+    uid_set: &str,
+    query: &str,
+) -> Result<()>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    let store_error = |source| ImapError::Store {
+        mailbox: mailbox.to_string(),
+        uids: uid_set.to_string(),
+        query: query.to_string(),
+        source,
+    };
+    let updates: Vec<_> = fetch_session
+        .uid_store(uid_set, query)
+        .await
+        .map_err(store_error)?
+        .collect()
+        .await;
+    for update in updates {
+        update.map_err(store_error)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_terminal_action_wins() {
+        let actions = vec![
+            MessageAction::Move("Quarantine".to_string()),
+            MessageAction::AddFlags(vec!["\\Seen".to_string()]),
+            MessageAction::Move("Archive".to_string()),
+            MessageAction::Copy("Backup".to_string()),
+            MessageAction::Delete,
+            MessageAction::AddFlags(vec!["\\Seen".to_string()]),
+        ];
+        assert_eq!(
+            settle_terminal(1, actions),
+            vec![
+                MessageAction::Move("Quarantine".to_string()),
+                MessageAction::AddFlags(vec!["\\Seen".to_string()]),
+                MessageAction::Copy("Backup".to_string()),
+            ]
+        );
+
+        let actions = vec![MessageAction::Delete, MessageAction::Delete];
+        assert_eq!(settle_terminal(1, actions), vec![MessageAction::Delete]);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::AttachmentsConfig;
+use crate::event::{AttachmentEvent, AttachmentMeta};
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use mail_parser::{MessagePart, MimeHeaders, PartType};
+use sha2::{Digest, Sha256};
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::borrow::Cow;
+
+// Metadata of every attachment for the parent record along with the child records
+// carrying the decoded attachments that pass the size limit and MIME allow / deny lists
+pub(crate) fn extract(
+    config: &AttachmentsConfig,
+    mailbox: &str,
+    uid: &str,
+    raw: &[u8],
+) -> Result<(Vec<AttachmentMeta>, Vec<String>)> {
+    let message = match mail_parser::MessageParser::default().parse(raw) {
+        Some(message) => message,
+        None => return Ok((vec![], vec![])),
+    };
+
+    let mut metas = vec![];
+    let mut children = vec![];
+    for (index, part) in message.attachments().enumerate() {
+        let contents = part.contents();
+        let mut meta = AttachmentMeta {
+            index,
+            filename: part.attachment_name().map(|name| name.to_string()),
+            content_type: content_type(part),
+            size: contents.len(),
+            sha256: crate::util::hex_encode(&Sha256::digest(contents)),
+            content_id: part.content_id().map(|id| id.to_string()),
+            emitted: None,
+        };
+
+        if config.records {
+            let emit = emit_allowed(config, &meta);
+            if emit {
+                let child = AttachmentEvent {
+                    mailbox: mailbox.to_string(),
+                    uid: uid.to_string(),
+                    attachment: meta.clone(),
+                    content_base64: BASE64.encode(contents),
+                };
+                children.push(serde_json::to_string(&child)?);
+            } else {
+                debug!(
+                    "Not emitting attachment {:?} of UID {}",
+                    &meta.filename, uid
+                );
+            }
+            meta.emitted = Some(emit);
+        }
+        metas.push(meta);
+    }
+    Ok((metas, children))
+}
+
+// Leave the attachment contents out of the parsed message - the metadata & child records carry them instead
+pub(crate) fn strip_contents(message: &mut mail_parser::Message<'_>) {
+    for part_id in message.attachments.clone() {
+        if let Some(part) = message.parts.get_mut(part_id) {
+            match &mut part.body {
+                PartType::Text(text) | PartType::Html(text) => *text = Cow::Borrowed(""),
+                PartType::Binary(bytes) | PartType::InlineBinary(bytes) => {
+                    *bytes = Cow::Borrowed(&[])
+                }
+                // The attachments of attached messages are left out the same way
+                PartType::Message(nested) => strip_contents(nested),
+                // Multiparts are made up of further parts
+                PartType::Multipart(_) => {}
+            }
+        }
+    }
+}
+
+pub(crate) fn content_type(part: &MessagePart) -> String {
+    match part.content_type() {
+        Some(ct) => match ct.subtype() {
+            Some(subtype) => format!("{}/{}", ct.ctype(), subtype).to_lowercase(),
+            None => ct.ctype().to_lowercase(),
+        },
+        None => "application/octet-stream".to_string(),
+    }
+}
+
+fn emit_allowed(config: &AttachmentsConfig, meta: &AttachmentMeta) -> bool {
+    if config.max_size.is_some_and(|max_size| meta.size > max_size) {
+        return false;
+    }
+    if config
+        .deny
+        .iter()
+        .any(|pattern| mime_matches(pattern, &meta.content_type))
+    {
+        return false;
+    }
+    config.allow.is_empty()
+        || config
+            .allow
+            .iter()
+            .any(|pattern| mime_matches(pattern, &meta.content_type))
+}
+
+// e.g. application/pdf or image/*
+fn mime_matches(pattern: &str, content_type: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(ty) => content_type.split('/').next() == Some(ty),
+        None => pattern == "*" || pattern == content_type,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "From: alice@example.com\r\n\
+        To: bob@example.org\r\n\
+        Subject: Attachments\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+        \r\n\
+        --outer\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        See attached.\r\n\
+        --outer\r\n\
+        Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+        Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        JVBERi0xLjQK\r\n\
+        --outer\r\n\
+        Content-Type: image/PNG\r\n\
+        Content-Disposition: attachment; filename=\"logo.png\"\r\n\
+        Content-ID: <logo@example.com>\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        iVBORw0KGgoAAAAN\r\n\
+        --outer\r\n\
+        Content-Type: message/rfc822\r\n\
+        Content-Disposition: attachment; filename=\"forwarded.eml\"\r\n\
+        \r\n\
+        From: carol@example.net\r\n\
+        Subject: Forwarded\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/mixed; boundary=\"inner\"\r\n\
+        \r\n\
+        --inner\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        Forwarded text.\r\n\
+        --inner\r\n\
+        Content-Type: text/csv\r\n\
+        Content-Disposition: attachment; filename=\"numbers.csv\"\r\n\
+        \r\n\
+        quarter,revenue\r\n\
+        --inner--\r\n\
+        --outer--\r\n";
+
+    fn meta(content_type: &str, size: usize) -> AttachmentMeta {
+        AttachmentMeta {
+            index: 0,
+            filename: None,
+            content_type: content_type.to_string(),
+            size,
+            sha256: String::new(),
+            content_id: None,
+            emitted: None,
+        }
+    }
+
+    #[test]
+    fn mime_patterns() {
+        assert!(mime_matches("application/pdf", "application/pdf"));
+        assert!(mime_matches("Application/PDF", "application/pdf"));
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(mime_matches("*", "text/csv"));
+        assert!(!mime_matches("image/*", "application/pdf"));
+        assert!(!mime_matches("image/*", "imagex/png"));
+        assert!(!mime_matches("application/pdf", "application/pdfx"));
+        assert!(!mime_matches("text", "text/plain"));
+    }
+
+    #[test]
+    fn allow_deny_and_max_size() {
+        let config = AttachmentsConfig {
+            records: true,
+            max_size: Some(100),
+            allow: vec!["image/*".to_string(), "application/pdf".to_string()],
+            deny: vec!["image/svg+xml".to_string()],
+        };
+        assert!(emit_allowed(&config, &meta("image/png", 100)));
+        assert!(emit_allowed(&config, &meta("application/pdf", 1)));
+        assert!(!emit_allowed(&config, &meta("image/png", 101)));
+        assert!(!emit_allowed(&config, &meta("image/svg+xml", 1)));
+        assert!(!emit_allowed(&config, &meta("text/csv", 1)));
+
+        // Everything not denied when there is no allow list
+        let config = AttachmentsConfig {
+            deny: vec!["image/*".to_string()],
+            ..Default::default()
+        };
+        assert!(emit_allowed(&config, &meta("text/csv", usize::MAX)));
+        assert!(!emit_allowed(&config, &meta("image/png", 1)));
+    }
+
+    #[test]
+    fn extract_metadata_and_children() {
+        let config = AttachmentsConfig {
+            records: true,
+            deny: vec!["image/*".to_string()],
+            ..Default::default()
+        };
+        let (metas, children) = extract(&config, "INBOX", "7", MESSAGE.as_bytes()).unwrap();
+
+        let names: Vec<_> = metas.iter().map(|m| m.filename.as_deref()).collect();
+        assert_eq!(
+            names,
+            [Some("report.pdf"), Some("logo.png"), Some("forwarded.eml")]
+        );
+        assert_eq!(metas[0].content_type, "application/pdf");
+        assert_eq!(metas[0].size, 9);
+        assert_eq!(
+            metas[0].sha256,
+            crate::util::hex_encode(&Sha256::digest(b"%PDF-1.4\n"))
+        );
+        assert_eq!(metas[0].emitted, Some(true));
+        assert_eq!(metas[1].content_type, "image/png");
+        assert_eq!(metas[1].content_id.as_deref(), Some("logo@example.com"));
+        assert_eq!(metas[1].emitted, Some(false));
+        assert_eq!(metas[2].content_type, "message/rfc822");
+
+        assert_eq!(children.len(), 2);
+        let child: AttachmentEvent = serde_json::from_str(&children[0]).unwrap();
+        assert_eq!(child.uid, "7");
+        assert_eq!(child.attachment.sha256, metas[0].sha256);
+        assert_eq!(child.content_base64, "JVBERi0xLjQK");
+
+        // Metadata only without child records
+        let (metas, children) = extract(
+            &AttachmentsConfig::default(),
+            "INBOX",
+            "7",
+            MESSAGE.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(metas.len(), 3);
+        assert!(metas.iter().all(|m| m.emitted.is_none()));
+        assert!(children.is_empty());
+    }
+
+    #[test]
+    fn strips_nested_attachments() {
+        let mut message = mail_parser::MessageParser::default()
+            .parse(MESSAGE.as_bytes())
+            .unwrap();
+        strip_contents(&mut message);
+
+        assert_eq!(message.body_text(0).as_deref(), Some("See attached."));
+        assert!(message.attachment(0).unwrap().contents().is_empty());
+        let nested = message.attachment(2).unwrap().message().unwrap();
+        assert_eq!(nested.body_text(0).as_deref(), Some("Forwarded text."));
+        assert!(nested.attachment(0).unwrap().contents().is_empty());
+    }
+}
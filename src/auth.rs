@@ -0,0 +1,506 @@
+use crate::config::{AuthConfig, AuthMechanism, ImapConfig, Secret};
+use crate::error::ImapError;
+use anyhow::{anyhow, bail, Result};
+use async_imap::error::Error as AsyncImapError;
+use async_imap::{Authenticator, Client as AsyncImapClient, Session as ImapSession};
+use serde::{Deserialize, Serialize};
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use async_std::io::{Read, Write};
+use core::fmt;
+use std::time::{Duration, Instant};
+
+// Refresh the access token this long before it expires
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+// Login or authenticate via SASL depending on the configured mechanism
+pub(crate) async fn login<T>(
+    client: AsyncImapClient<T>,
+    config: &ImapConfig,
+    tokens: &mut TokenProvider,
+) -> Result<ImapSession<T>>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    let mechanism = match &config.auth {
+        Some(auth) => &auth.mechanism,
+        None => &AuthMechanism::Login,
+    };
+
+    let session = match mechanism {
+        AuthMechanism::Login => {
+            let password = config
+                .password
+                .as_ref()
+                .ok_or_else(|| anyhow!("password must be set for LOGIN authentication"))?;
+            client
+                .login(&config.user, password.expose())
+                .await
+                .map_err(|(source, _client)| ImapError::Auth {
+                    user: config.user.clone(),
+                    source,
+                })?
+        }
+        AuthMechanism::Xoauth2 => {
+            authenticate(client, config, tokens, "XOAUTH2", |access_token| {
+                XOAuth2::new(&config.user, access_token)
+            })
+            .await?
+        }
+        AuthMechanism::Oauthbearer => {
+            authenticate(client, config, tokens, "OAUTHBEARER", |access_token| {
+                OAuthBearer::new(&config.user, &config.host, &config.port, access_token)
+            })
+            .await?
+        }
+    };
+    Ok(session)
+}
+
+// SASL with the access token - a token rejected before it was due to expire e.g. when revoked
+// is refreshed and tried once more before giving up
+async fn authenticate<T, A>(
+    mut client: AsyncImapClient<T>,
+    config: &ImapConfig,
+    tokens: &mut TokenProvider,
+    mechanism: &str,
+    authenticator: impl Fn(&str) -> A,
+) -> Result<ImapSession<T>>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+    A: Authenticator,
+{
+    let mut refreshed = false;
+    loop {
+        let access_token = tokens.access_token().await?;
+        match client
+            .authenticate(mechanism, authenticator(&access_token))
+            .await
+        {
+            Ok(session) => return Ok(session),
+            Err((AsyncImapError::No(reason), returned)) if !refreshed && tokens.can_refresh() => {
+                warn!(
+                    "{} rejected the access token: {} - Refreshing and retrying once.",
+                    mechanism, reason
+                );
+                tokens.invalidate();
+                refreshed = true;
+                client = returned;
+            }
+            Err((source, _client)) => {
+                return Err(ImapError::Auth {
+                    user: config.user.clone(),
+                    source,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// SASL XOAUTH2 as used by Gmail & Microsoft 365
+pub(crate) struct XOAuth2 {
+    initial_response: String,
+    sent: bool,
+}
+
+impl XOAuth2 {
+    pub(crate) fn new(user: &str, access_token: &str) -> Self {
+        Self {
+            initial_response: format!("user={}\x01auth=Bearer {}\x01\x01", user, access_token),
+            sent: false,
+        }
+    }
+}
+
+impl Authenticator for XOAuth2 {
+    type Response = String;
+
+    fn process(&mut self, challenge: &[u8]) -> Self::Response {
+        if self.sent {
+            // Server sent us the error details - empty response makes it finish with NO
+            warn!("XOAUTH2 error: {}", String::from_utf8_lossy(challenge));
+            return String::new();
+        }
+        self.sent = true;
+        self.initial_response.clone()
+    }
+}
+
+// SASL OAUTHBEARER - RFC 7628
+pub(crate) struct OAuthBearer {
+    initial_response: String,
+    sent: bool,
+}
+
+impl OAuthBearer {
+    pub(crate) fn new(user: &str, host: &str, port: &str, access_token: &str) -> Self {
+        Self {
+            initial_response: format!(
+                "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+                gs2_saslname(user),
+                host,
+                port,
+                access_token
+            ),
+            sent: false,
+        }
+    }
+}
+
+impl Authenticator for OAuthBearer {
+    type Response = String;
+
+    fn process(&mut self, challenge: &[u8]) -> Self::Response {
+        if self.sent {
+            // RFC 7628 3.2.3 - error challenge is acknowledged with a lone %x01
+            warn!("OAUTHBEARER error: {}", String::from_utf8_lossy(challenge));
+            return "\x01".to_string();
+        }
+        self.sent = true;
+        self.initial_response.clone()
+    }
+}
+
+// RFC 5801 saslname - ',' and '=' must be escaped in the GS2 header
+fn gs2_saslname(user: &str) -> String {
+    user.replace('=', "=3D").replace(',', "=2C")
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    access_token: Secret,
+    expires_at: Option<Instant>,
+}
+
+impl CachedToken {
+    fn is_fresh(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + TOKEN_EXPIRY_MARGIN < expires_at,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RefreshRequest<'a> {
+    grant_type: &'a str,
+    refresh_token: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    // Some providers rotate the refresh token upon use
+    refresh_token: Option<String>,
+}
+
+// Hands out OAuth2 access tokens - refreshing them via the token endpoint when expired
+#[derive(Debug, Default)]
+pub(crate) struct TokenProvider {
+    auth: Option<AuthConfig>,
+    cached: Option<CachedToken>,
+}
+
+impl TokenProvider {
+    pub(crate) fn new(auth: Option<AuthConfig>) -> Self {
+        Self { auth, cached: None }
+    }
+
+    pub(crate) async fn access_token(&mut self) -> Result<String> {
+        if let Some(cached) = &self.cached {
+            if cached.is_fresh() {
+                return Ok(cached.access_token.expose().to_string());
+            }
+            debug!("Access token expired - Refreshing.");
+        }
+
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => bail!("auth section is required for OAuth2 authentication"),
+        };
+
+        let cached = match (&auth.token_endpoint, &auth.refresh_token) {
+            (Some(token_endpoint), Some(refresh_token)) => {
+                let response = refresh(auth, token_endpoint, refresh_token.expose()).await?;
+                if let (Some(auth), Some(rotated)) = (&mut self.auth, response.refresh_token) {
+                    auth.refresh_token = Some(rotated.into());
+                }
+                CachedToken {
+                    access_token: response.access_token.into(),
+                    expires_at: response
+                        .expires_in
+                        .map(|secs| Instant::now() + Duration::from_secs(secs)),
+                }
+            }
+            _ => match &auth.access_token {
+                Some(access_token) => CachedToken {
+                    access_token: access_token.clone(),
+                    expires_at: None,
+                },
+                None => bail!(
+                    "auth requires either access_token or refresh_token together with token_endpoint"
+                ),
+            },
+        };
+
+        let access_token = cached.access_token.expose().to_string();
+        self.cached = Some(cached);
+        Ok(access_token)
+    }
+
+    // Whether a new access token can be had from the token endpoint
+    pub(crate) fn can_refresh(&self) -> bool {
+        self.auth
+            .as_ref()
+            .is_some_and(|auth| auth.token_endpoint.is_some() && auth.refresh_token.is_some())
+    }
+
+    // Forget the cached access token e.g. once the server rejected it
+    pub(crate) fn invalidate(&mut self) {
+        self.cached = None;
+    }
+}
+
+async fn refresh(
+    auth: &AuthConfig,
+    token_endpoint: &str,
+    refresh_token: &str,
+) -> Result<TokenResponse> {
+    info!("Refreshing OAuth2 access token from {}", token_endpoint);
+
+    let request = RefreshRequest {
+        grant_type: "refresh_token",
+        refresh_token,
+        client_id: auth.client_id.as_deref(),
+        client_secret: auth.client_secret.as_ref().map(Secret::expose),
+        scope: auth.scope.as_deref(),
+    };
+    let body = surf::Body::from_form(&request).map_err(|e| anyhow!("{}", e))?;
+
+    let mut response = surf::post(token_endpoint)
+        .body(body)
+        .await
+        .map_err(|e| anyhow!("Token endpoint request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let details = response.body_string().await.unwrap_or_default();
+        bail!(
+            "Token endpoint responded with {}: {}",
+            response.status(),
+            details
+        );
+    }
+
+    response
+        .body_json()
+        .await
+        .map_err(|e| anyhow!("Invalid token endpoint response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_imap::MockImapServer;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+    use async_std::task::{spawn, JoinHandle};
+
+    // Serves the canned responses in order, one per connection, and hands back the requests
+    async fn mock_token_server(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/token", listener.local_addr().unwrap());
+
+        let handle = spawn(async move {
+            let mut requests = vec![];
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut stream).await);
+
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (endpoint, handle)
+    }
+
+    async fn read_request(stream: &mut async_std::net::TcpStream) -> String {
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|l| {
+                        let (name, value) = l.split_once(':')?;
+                        match name.eq_ignore_ascii_case("content-length") {
+                            true => value.trim().parse::<usize>().ok(),
+                            false => None,
+                        }
+                    })
+                    .unwrap_or(0);
+                if request.len() >= header_end + 4 + content_length {
+                    return text;
+                }
+            }
+            if n == 0 {
+                return String::from_utf8_lossy(&request).to_string();
+            }
+        }
+    }
+
+    fn auth_config(token_endpoint: &str) -> AuthConfig {
+        AuthConfig {
+            mechanism: AuthMechanism::Xoauth2,
+            access_token: None,
+            refresh_token: Some("refresh-1".to_string().into()),
+            token_endpoint: Some(token_endpoint.to_string()),
+            client_id: Some("client".to_string()),
+            client_secret: Some("secret".to_string().into()),
+            scope: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn refreshes_and_caches_access_token() {
+        let (endpoint, server) = mock_token_server(vec![(
+            "200 OK",
+            r#"{"access_token":"token-1","expires_in":3600,"token_type":"Bearer"}"#,
+        )])
+        .await;
+
+        let mut tokens = TokenProvider::new(Some(auth_config(&endpoint)));
+        assert_eq!(tokens.access_token().await.unwrap(), "token-1");
+        assert_eq!(tokens.access_token().await.unwrap(), "token-1");
+
+        let requests = server.await;
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("POST /token"));
+        assert!(requests[0].contains("grant_type=refresh_token"));
+        assert!(requests[0].contains("refresh_token=refresh-1"));
+        assert!(requests[0].contains("client_id=client"));
+    }
+
+    #[async_std::test]
+    async fn refreshes_expired_token_with_rotated_refresh_token() {
+        let (endpoint, server) = mock_token_server(vec![
+            (
+                "200 OK",
+                r#"{"access_token":"token-1","expires_in":0,"refresh_token":"refresh-2"}"#,
+            ),
+            ("200 OK", r#"{"access_token":"token-2","expires_in":3600}"#),
+        ])
+        .await;
+
+        let mut tokens = TokenProvider::new(Some(auth_config(&endpoint)));
+        assert_eq!(tokens.access_token().await.unwrap(), "token-1");
+        assert_eq!(tokens.access_token().await.unwrap(), "token-2");
+
+        let requests = server.await;
+        assert!(requests[1].contains("refresh_token=refresh-2"));
+    }
+
+    #[async_std::test]
+    async fn token_endpoint_error_is_reported() {
+        let (endpoint, server) =
+            mock_token_server(vec![("400 Bad Request", r#"{"error":"invalid_grant"}"#)]).await;
+
+        let mut tokens = TokenProvider::new(Some(auth_config(&endpoint)));
+        let err = tokens.access_token().await.unwrap_err();
+        assert!(err.to_string().contains("invalid_grant"));
+        server.await;
+    }
+
+    // XOAUTH2 against the mock server accepting only the given access token
+    async fn xoauth2_login(
+        accepted_token: &str,
+        auth: serde_json::Value,
+    ) -> (
+        Result<ImapSession<crate::connection::ImapStream>>,
+        Vec<String>,
+    ) {
+        let server = MockImapServer::start("user", accepted_token, None).await;
+        let config =
+            crate::mock_imap::config(server.port, false, serde_json::json!({ "auth": auth }));
+        let client = crate::connection::connect(&config).await.unwrap();
+        let mut tokens = TokenProvider::new(config.auth.clone());
+        let res = login(client, &config, &mut tokens).await;
+        (res, server.commands())
+    }
+
+    #[async_std::test]
+    async fn refreshes_rejected_access_token() {
+        let (endpoint, server) = mock_token_server(vec![
+            ("200 OK", r#"{"access_token":"revoked","expires_in":3600}"#),
+            ("200 OK", r#"{"access_token":"token-2","expires_in":3600}"#),
+        ])
+        .await;
+
+        let (res, commands) = xoauth2_login(
+            "token-2",
+            serde_json::json!({
+                "mechanism": "xoauth2",
+                "refresh_token": "refresh-1",
+                "token_endpoint": endpoint
+            }),
+        )
+        .await;
+        assert!(res.is_ok(), "{:?}", res.err());
+        assert_eq!(commands, ["AUTHENTICATE XOAUTH2", "AUTHENTICATE XOAUTH2"]);
+        assert_eq!(server.await.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn rejected_static_access_token_is_fatal() {
+        let (res, commands) = xoauth2_login(
+            "token-2",
+            serde_json::json!({"mechanism": "xoauth2", "access_token": "revoked"}),
+        )
+        .await;
+        let err = res.err().unwrap();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Auth { .. }) => assert!(!err.is_retryable()),
+            _ => panic!("Expected an authentication failure: {:?}", err),
+        }
+        // Nothing to refresh it with - the same token would be rejected again
+        assert_eq!(commands, ["AUTHENTICATE XOAUTH2"]);
+    }
+
+    #[test]
+    fn sasl_initial_responses() {
+        let mut xoauth2 = XOAuth2::new("user@example.com", "tok");
+        assert_eq!(
+            xoauth2.process(b""),
+            "user=user@example.com\x01auth=Bearer tok\x01\x01"
+        );
+        assert_eq!(xoauth2.process(b"{\"status\":\"401\"}"), "");
+
+        let mut bearer = OAuthBearer::new("a,b=c", "imap.example.com", "993", "tok");
+        assert_eq!(
+            bearer.process(b""),
+            "n,a=a=2Cb=3Dc,\x01host=imap.example.com\x01port=993\x01auth=Bearer tok\x01\x01"
+        );
+        assert_eq!(bearer.process(b"{\"status\":\"invalid_token\"}"), "\x01");
+    }
+}
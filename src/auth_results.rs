@@ -0,0 +1,355 @@
+use crate::event::AuthResult;
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+// Every method result of every Authentication-Results header (RFC 8601) in the raw header section
+pub(crate) fn parse(raw_headers: &[u8]) -> Vec<AuthResult> {
+    crate::record::raw_header_values(raw_headers, "Authentication-Results")
+        .iter()
+        .flat_map(|value| parse_header(value))
+        .collect()
+}
+
+// The header section without the Authentication-Results headers of authserv-ids not in the trusted list,
+// along with how many were left out. Nothing is left out when the list is empty.
+pub(crate) fn trusted_headers<'a>(raw: &'a [u8], trusted: &[String]) -> (Cow<'a, [u8]>, u32) {
+    if trusted.is_empty() {
+        return (Cow::Borrowed(raw), 0);
+    }
+
+    let mut kept: Vec<u8> = Vec::with_capacity(raw.len());
+    let mut ignored = 0;
+    let mut lines = raw.split_inclusive(|b| *b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        // End of the header section - the body is kept as is
+        if line == b"\r\n" || line == b"\n" {
+            kept.extend(line);
+            for rest in lines {
+                kept.extend(rest);
+            }
+            break;
+        }
+
+        // Gather the continuation lines of the field
+        let mut field = line.to_vec();
+        while lines
+            .peek()
+            .is_some_and(|next| next.first().is_some_and(|b| *b == b' ' || *b == b'\t'))
+        {
+            if let Some(next) = lines.next() {
+                field.extend(next);
+            }
+        }
+
+        let field_str = String::from_utf8_lossy(&field);
+        let untrusted = match field_str.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("Authentication-Results") => {
+                let value = value.replace("\r\n", "").replace('\n', "");
+                match authserv_id(&value) {
+                    Some(id) => !trusted.iter().any(|t| t.eq_ignore_ascii_case(&id)),
+                    None => true,
+                }
+            }
+            _ => false,
+        };
+        match untrusted {
+            true => {
+                debug!("Ignoring untrusted {}", field_str.trim_end());
+                ignored += 1;
+            }
+            false => kept.extend(field),
+        }
+    }
+    (Cow::Owned(kept), ignored)
+}
+
+fn authserv_id(value: &str) -> Option<String> {
+    let first = split_outside(value, ';').into_iter().next()?;
+    tokenize(&first).0.into_iter().next()
+}
+
+// authserv-id [version] ; method=result [reason=...] [ptype.property=value ...] ; ...
+pub(crate) fn parse_header(value: &str) -> Vec<AuthResult> {
+    let mut statements = split_outside(value, ';').into_iter();
+
+    let authserv_id = match statements
+        .next()
+        .and_then(|first| tokenize(&first).0.into_iter().next())
+    {
+        Some(authserv_id) => authserv_id,
+        None => return vec![],
+    };
+
+    let mut results = vec![];
+    for statement in statements {
+        let (tokens, comments) = tokenize(&statement);
+        let mut tokens = tokens.into_iter();
+
+        let (method, result) = match tokens.next().as_deref().and_then(|t| t.split_once('=')) {
+            Some((method, result)) => (
+                // Drop the method version e.g. dkim/1
+                method.split('/').next().unwrap_or(method).to_lowercase(),
+                result.to_lowercase(),
+            ),
+            // "none" when no authentication was carried out
+            None => continue,
+        };
+
+        let mut reason = None;
+        let mut properties = BTreeMap::new();
+        for token in tokens {
+            if let Some((name, value)) = token.split_once('=') {
+                let name = name.to_lowercase();
+                match name.as_str() {
+                    "reason" => reason = Some(value.to_string()),
+                    _ => {
+                        properties.insert(name, value.to_string());
+                    }
+                }
+            }
+        }
+
+        let (domain, selector, policy) = describe(&method, &properties, &comments);
+        results.push(AuthResult {
+            authserv_id: authserv_id.clone(),
+            method,
+            result,
+            reason,
+            domain,
+            selector,
+            policy,
+            properties,
+        });
+    }
+    results
+}
+
+// Pull the commonly needed properties of each method up - domain, selector & policy
+fn describe(
+    method: &str,
+    properties: &BTreeMap<String, String>,
+    comments: &[String],
+) -> (Option<String>, Option<String>, Option<String>) {
+    let property = |name: &str| properties.get(name).map(|value| value.to_lowercase());
+    let domain_of = |address: String| address.rsplit('@').next().unwrap_or_default().to_string();
+    match method {
+        "dkim" => (
+            property("header.d").or_else(|| property("header.i").map(domain_of)),
+            property("header.s"),
+            None,
+        ),
+        "spf" => (
+            property("smtp.mailfrom")
+                .map(domain_of)
+                .or_else(|| property("smtp.helo")),
+            None,
+            None,
+        ),
+        "dmarc" => (
+            property("header.from"),
+            None,
+            // policy.dmarc where given - otherwise the p= many providers put into the comment
+            property("policy.dmarc").or_else(|| {
+                comments
+                    .iter()
+                    .flat_map(|comment| comment.split_whitespace())
+                    .find_map(|word| word.strip_prefix("p="))
+                    .map(|policy| policy.to_lowercase())
+            }),
+        ),
+        _ => (None, None, None),
+    }
+}
+
+// Split on the separator outside of quoted strings and comments
+fn split_outside(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && (quoted || depth > 0) {
+            escaped = true;
+        } else if c == '"' && depth == 0 {
+            quoted = !quoted;
+        } else if c == '(' && !quoted {
+            depth += 1;
+        } else if c == ')' && !quoted && depth > 0 {
+            depth -= 1;
+        } else if c == separator && !quoted && depth == 0 {
+            parts.push(std::mem::take(&mut current));
+            continue;
+        }
+        current.push(c);
+    }
+    parts.push(current);
+    parts
+}
+
+// Whitespace separated tokens with quotes removed and `name = value` joined,
+// along with the text of the comments
+fn tokenize(statement: &str) -> (Vec<String>, Vec<String>) {
+    let mut tokens: Vec<String> = vec![];
+    let mut comments = vec![];
+    let mut current = String::new();
+    let mut comment = String::new();
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if escaped {
+            match depth > 0 {
+                true => comment.push(c),
+                false => current.push(c),
+            }
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted || depth > 0 => escaped = true,
+            '(' if !quoted => {
+                if depth > 0 {
+                    comment.push(c);
+                }
+                depth += 1;
+            }
+            ')' if !quoted && depth > 0 => {
+                depth -= 1;
+                match depth {
+                    0 => comments.push(std::mem::take(&mut comment).trim().to_string()),
+                    _ => comment.push(c),
+                }
+            }
+            _ if depth > 0 => comment.push(c),
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                // Keep name = value together
+                while chars.peek().is_some_and(|n| n.is_whitespace()) {
+                    chars.next();
+                }
+                let joins = current.ends_with('=') || chars.peek() == Some(&'=');
+                if !joins && !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    (tokens, comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gmail_style_header() {
+        let results = parse_header(
+            "mx.google.com; dkim=pass header.i=@example.com header.s=20230601 header.b=AbCdEf; \
+             spf=pass (google.com: domain of alice@example.com designates 192.0.2.1 as permitted sender) smtp.mailfrom=alice@example.com; \
+             dmarc=pass (p=REJECT sp=REJECT dis=NONE) header.from=example.com; \
+             arc=pass (i=1 spf=pass spfdomain=example.com dkim=pass dkdomain=example.com)",
+        );
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(|r| r.authserv_id == "mx.google.com"));
+
+        let dkim = &results[0];
+        assert_eq!(
+            (dkim.method.as_str(), dkim.result.as_str()),
+            ("dkim", "pass")
+        );
+        assert_eq!(dkim.domain.as_deref(), Some("example.com"));
+        assert_eq!(dkim.selector.as_deref(), Some("20230601"));
+        assert_eq!(
+            dkim.properties.get("header.b").map(|b| b.as_str()),
+            Some("AbCdEf")
+        );
+
+        let spf = &results[1];
+        assert_eq!((spf.method.as_str(), spf.result.as_str()), ("spf", "pass"));
+        assert_eq!(spf.domain.as_deref(), Some("example.com"));
+
+        let dmarc = &results[2];
+        assert_eq!(
+            (dmarc.method.as_str(), dmarc.result.as_str()),
+            ("dmarc", "pass")
+        );
+        assert_eq!(dmarc.domain.as_deref(), Some("example.com"));
+        assert_eq!(dmarc.policy.as_deref(), Some("reject"));
+
+        let arc = &results[3];
+        assert_eq!((arc.method.as_str(), arc.result.as_str()), ("arc", "pass"));
+    }
+
+    #[test]
+    fn rfc8601_examples() {
+        // Version, reason, quoting and whitespace around =
+        let results = parse_header(
+            "example.com 1; dkim/1 = fail reason=\"signature; did not verify\" header.d=Example.NET header.s=sel; \
+             dmarc=fail policy.dmarc=quarantine header.from=example.net",
+        );
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].authserv_id, "example.com");
+        assert_eq!(results[0].method, "dkim");
+        assert_eq!(results[0].result, "fail");
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("signature; did not verify")
+        );
+        assert_eq!(results[0].domain.as_deref(), Some("example.net"));
+        assert_eq!(results[1].policy.as_deref(), Some("quarantine"));
+
+        assert!(parse_header("example.org; none").is_empty());
+        assert!(parse_header("").is_empty());
+    }
+
+    #[test]
+    fn untrusted_ignored() {
+        let raw = b"Authentication-Results: mx.example.org; dkim=pass header.d=example.com\r\n\
+            Authentication-Results: forged.example.net;\r\n\tdkim=pass header.d=bank.example\r\n\
+            Subject: test\r\n\r\n\
+            Authentication-Results: in the body\r\n";
+
+        let trusted = vec!["MX.example.org".to_string()];
+        let (kept, ignored) = trusted_headers(raw, &trusted);
+        assert_eq!(ignored, 1);
+        assert_eq!(
+            &kept[..],
+            b"Authentication-Results: mx.example.org; dkim=pass header.d=example.com\r\n\
+            Subject: test\r\n\r\n\
+            Authentication-Results: in the body\r\n"
+        );
+        let results = parse(&kept);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].domain.as_deref(), Some("example.com"));
+
+        let (kept, ignored) = trusted_headers(raw, &[]);
+        assert_eq!((&kept[..], ignored), (&raw[..], 0));
+    }
+
+    #[test]
+    fn all_headers() {
+        let raw =
+            b"Authentication-Results: mx.example.org;\r\n\tspf=fail smtp.mailfrom=example.com\r\n\
+            Authentication-Results: other.example.org; dkim=none\r\n\
+            Subject: test\r\n\r\n";
+        let results = parse(raw);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].authserv_id, "mx.example.org");
+        assert_eq!(results[0].result, "fail");
+        assert_eq!(results[1].authserv_id, "other.example.org");
+        assert_eq!(results[1].method, "dkim");
+    }
+}
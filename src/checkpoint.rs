@@ -0,0 +1,227 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Position within a single mailbox - UIDs are only comparable while UIDVALIDITY stays the same
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MailboxCheckpoint {
+    pub uid_validity: u32,
+    pub last_uid: u32,
+    // HIGHESTMODSEQ (RFC 7162) seen upon the last completed sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highest_modseq: Option<u64>,
+}
+
+// Checkpoints for every mailbox we have produced records from,
+// optionally persisted as JSON so that a restart resumes where we left off
+#[derive(Debug, Default)]
+pub(crate) struct Checkpoints {
+    path: Option<PathBuf>,
+    mailboxes: HashMap<String, MailboxCheckpoint>,
+}
+
+impl Checkpoints {
+    pub(crate) fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                warn!("checkpoint_file was not set - checkpoints are kept in memory only and a restart will re-produce messages.");
+                return Ok(Self::default());
+            }
+        };
+
+        let mailboxes = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    "No checkpoint found at {:?} - starting from scratch.",
+                    &path
+                );
+                HashMap::new()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        debug!("Loaded checkpoints {:?}", &mailboxes);
+
+        Ok(Self {
+            path: Some(path),
+            mailboxes,
+        })
+    }
+
+    pub(crate) fn get(&self, mailbox: &str) -> Option<&MailboxCheckpoint> {
+        self.mailboxes.get(mailbox)
+    }
+
+    // Compare the UIDVALIDITY the server reported upon SELECT against the checkpoint
+    // and return the first UID we have not yet produced.
+    // If UIDVALIDITY changed all the stored UIDs are meaningless and we start over.
+    pub(crate) fn validate(&mut self, mailbox: &str, uid_validity: Option<u32>) -> Result<u32> {
+        let uid_validity = match uid_validity {
+            Some(uid_validity) => uid_validity,
+            None => {
+                warn!(
+                    "Server did not report UIDVALIDITY for {} - cannot validate checkpoint.",
+                    mailbox
+                );
+                return Ok(self
+                    .get(mailbox)
+                    .map(|c| c.last_uid)
+                    .unwrap_or(0)
+                    .saturating_add(1));
+            }
+        };
+
+        match self.mailboxes.get_mut(mailbox) {
+            Some(checkpoint) if checkpoint.uid_validity == uid_validity => {
+                Ok(checkpoint.last_uid.saturating_add(1))
+            }
+            Some(checkpoint) => {
+                warn!(
+                    "UIDVALIDITY of {} changed {} -> {} - starting over from the first message.",
+                    mailbox, checkpoint.uid_validity, uid_validity
+                );
+                *checkpoint = MailboxCheckpoint {
+                    uid_validity,
+                    ..Default::default()
+                };
+                self.persist()?;
+                Ok(1)
+            }
+            None => {
+                self.mailboxes.insert(
+                    mailbox.to_string(),
+                    MailboxCheckpoint {
+                        uid_validity,
+                        ..Default::default()
+                    },
+                );
+                Ok(1)
+            }
+        }
+    }
+
+    // Record that the given UID has been produced - only ever moves forward
+    pub(crate) fn advance(&mut self, mailbox: &str, uid: u32) {
+        let checkpoint = self.mailboxes.entry(mailbox.to_string()).or_default();
+        if uid > checkpoint.last_uid {
+            checkpoint.last_uid = uid;
+        }
+    }
+
+    // Record the HIGHESTMODSEQ up to which the mailbox has been synced
+    pub(crate) fn set_modseq(&mut self, mailbox: &str, highest_modseq: Option<u64>) {
+        let checkpoint = self.mailboxes.entry(mailbox.to_string()).or_default();
+        checkpoint.highest_modseq = highest_modseq;
+    }
+
+    pub(crate) fn persist(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        // Write & rename so that a crash never leaves a truncated checkpoint behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(&self.mailboxes)?)?;
+        std::fs::rename(&tmp_path, path)?;
+
+        trace!("Persisted checkpoints to {:?}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A checkpoint file of its own per test as the tests run in parallel
+    fn checkpoint_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("imap-source-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn uid_validity_reset() {
+        let mut checkpoints = Checkpoints::default();
+        assert_eq!(checkpoints.validate("INBOX", Some(7)).unwrap(), 1);
+        checkpoints.advance("INBOX", 10);
+        checkpoints.set_modseq("INBOX", Some(100));
+        assert_eq!(checkpoints.validate("INBOX", Some(7)).unwrap(), 11);
+
+        // The old UIDs and modseq are meaningless under the new UIDVALIDITY
+        assert_eq!(checkpoints.validate("INBOX", Some(8)).unwrap(), 1);
+        assert_eq!(
+            checkpoints.get("INBOX"),
+            Some(&MailboxCheckpoint {
+                uid_validity: 8,
+                last_uid: 0,
+                highest_modseq: None,
+            })
+        );
+
+        // Without UIDVALIDITY the checkpoint is taken as it is
+        checkpoints.advance("INBOX", 3);
+        assert_eq!(checkpoints.validate("INBOX", None).unwrap(), 4);
+        assert_eq!(checkpoints.validate("Unknown", None).unwrap(), 1);
+    }
+
+    #[test]
+    fn advance_is_monotonic() {
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.validate("INBOX", Some(7)).unwrap();
+        checkpoints.advance("INBOX", 5);
+        checkpoints.advance("INBOX", 3);
+        assert_eq!(checkpoints.get("INBOX").unwrap().last_uid, 5);
+        checkpoints.advance("INBOX", 6);
+        assert_eq!(checkpoints.validate("INBOX", Some(7)).unwrap(), 7);
+
+        checkpoints.advance("INBOX", u32::MAX);
+        assert_eq!(checkpoints.validate("INBOX", Some(7)).unwrap(), u32::MAX);
+        assert_eq!(checkpoints.validate("INBOX", None).unwrap(), u32::MAX);
+    }
+
+    #[test]
+    fn persist_and_load() {
+        let path = checkpoint_path("persist");
+        let path_str = path.to_str().unwrap();
+
+        let mut checkpoints = Checkpoints::load(Some(path_str)).unwrap();
+        assert_eq!(checkpoints.get("INBOX"), None);
+        checkpoints.validate("INBOX", Some(7)).unwrap();
+        checkpoints.advance("INBOX", 42);
+        checkpoints.set_modseq("INBOX", Some(1234));
+        checkpoints.validate("Archive", Some(9)).unwrap();
+        checkpoints.persist().unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = Checkpoints::load(Some(path_str)).unwrap();
+        assert_eq!(loaded.mailboxes, checkpoints.mailboxes);
+        assert_eq!(
+            loaded.get("INBOX"),
+            Some(&MailboxCheckpoint {
+                uid_validity: 7,
+                last_uid: 42,
+                highest_modseq: Some(1234),
+            })
+        );
+
+        // A changed UIDVALIDITY is persisted right away
+        let mut loaded = loaded;
+        loaded.validate("INBOX", Some(8)).unwrap();
+        let reloaded = Checkpoints::load(Some(path_str)).unwrap();
+        assert_eq!(reloaded.get("INBOX").unwrap().last_uid, 0);
+        assert_eq!(reloaded.get("INBOX").unwrap().uid_validity, 8);
+
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(Checkpoints::load(Some(path_str)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,26 +1,364 @@
 use fluvio_connector_common::connector;
+use serde::Deserialize;
+
+use std::fmt;
 
 #[connector(config, name = "imap")]
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) struct ImapConfig {
+pub struct ImapConfig {
     pub host: String,
     pub port: String,
     pub user: String,
-    pub password: String,
-    pub mailbox: String,
+    pub password: Option<Secret>,
+    pub mailbox: MailboxSelection,
+    pub mailbox_topic: Option<String>,
+    pub record_key: Option<RecordKeyConfig>,
     pub search: String,
     pub fetch: String,
     pub mode_bytes: bool,
     pub mode_utf8_lossy: bool,
     pub mode_parser: bool,
+    #[serde(default)]
+    pub mode_normalized: bool,
     pub mode_dkim_auth: bool,
+    #[serde(default)]
+    pub dkim_verify: bool,
+    #[serde(default)]
+    pub mode_auth_results: bool,
+    #[serde(default)]
+    pub dmarc_move: Vec<DmarcMoveConfig>,
+    #[serde(default)]
+    pub trusted_authserv_ids: Vec<String>,
     pub dkim_authenticated_move: Option<String>,
     pub dkim_unauthenticated_move: Option<String>,
     #[serde(default = "default_idle")]
     pub idle_timeout: u64,
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default = "default_fetch_batch_size")]
+    pub fetch_batch_size: usize,
     pub dangerous_cert: bool,
+    #[serde(default)]
+    pub tls_mode: TlsMode,
+    #[serde(default)]
+    pub allow_insecure_plaintext: bool,
+    pub tls_ca_bundle: Option<String>,
+    pub tls_client_cert: Option<String>,
+    pub tls_client_key: Option<String>,
+    #[serde(default)]
+    pub tls_pin_cert_sha256: Vec<String>,
+    #[serde(default)]
+    pub tls_pin_spki_sha256: Vec<String>,
+    pub checkpoint_file: Option<String>,
+    pub dead_letter_topic: Option<String>,
+    pub topic_producer: Option<TopicProducerConfig>,
+    pub quarantine_mailbox: Option<String>,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub on_produced: Vec<OnProducedConfig>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    pub attachments: Option<AttachmentsConfig>,
+}
+
+impl ImapConfig {
+    // Mailboxes messages may be moved or copied into
+    pub(crate) fn destination_mailboxes(&self) -> Vec<&String> {
+        let mut destinations: Vec<&String> = self
+            .dkim_authenticated_move
+            .iter()
+            .chain(self.dkim_unauthenticated_move.iter())
+            .collect();
+        destinations.extend(self.dmarc_move.iter().map(|m| &m.mailbox));
+        destinations.extend(self.quarantine_mailbox.iter());
+        for on_produced in self.on_produced.iter() {
+            match &on_produced.action {
+                ActionConfig::Copy { mailbox } | ActionConfig::Move { mailbox } => {
+                    destinations.push(mailbox)
+                }
+                _ => {}
+            }
+        }
+        for rule in self.rules.iter() {
+            for action in rule.actions.iter() {
+                match action {
+                    RuleActionConfig::Copy(mailbox) | RuleActionConfig::Move(mailbox) => {
+                        destinations.push(mailbox)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        destinations
+    }
+
+    // Deleting relies on UID EXPUNGE
+    pub(crate) fn deletes_messages(&self) -> bool {
+        self.on_produced
+            .iter()
+            .any(|on_produced| on_produced.action == ActionConfig::Delete)
+    }
+}
+
+// Attachment metadata in the record and optionally child records carrying the attachments
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct AttachmentsConfig {
+    #[serde(default)]
+    pub records: bool,
+    // Largest decoded attachment in bytes emitted as a child record
+    pub max_size: Option<usize>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+// Move into the mailbox when the DMARC result and / or policy match
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DmarcMoveConfig {
+    pub result: Option<String>,
+    pub policy: Option<String>,
+    pub mailbox: String,
+}
+
+// Action carried out on the server once the record of a message has been produced
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct OnProducedConfig {
+    #[serde(flatten)]
+    pub action: ActionConfig,
+    #[serde(default)]
+    pub when: ActionFilter,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ActionConfig {
+    AddFlags { flags: Vec<String> },
+    RemoveFlags { flags: Vec<String> },
+    Copy { mailbox: String },
+    Move { mailbox: String },
+    Delete,
+}
+
+// Producer settings of the mailbox_topic & dead_letter_topic topics
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct TopicProducerConfig {
+    pub linger_ms: Option<u64>,
+    pub batch_size: Option<usize>,
+    // none, gzip, snappy, lz4 or zstd
+    pub compression: Option<String>,
+}
+
+// Restricts an action to the matching messages - all set conditions must match
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ActionFilter {
+    pub mailbox: Option<String>,
+    pub dkim_authenticated: Option<bool>,
+    pub from_domain: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMechanism {
+    Login,
+    Xoauth2,
+    Oauthbearer,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct AuthConfig {
+    pub mechanism: AuthMechanism,
+    pub access_token: Option<Secret>,
+    pub refresh_token: Option<Secret>,
+    pub token_endpoint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<Secret>,
+    pub scope: Option<String>,
+}
+
+// Password, token or client secret - redacted in the Debug output the config is logged with
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+// A single mailbox or a list - entries may contain LIST wildcards e.g. Projects/*
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum MailboxSelection {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl MailboxSelection {
+    pub(crate) fn patterns(&self) -> &[String] {
+        match self {
+            MailboxSelection::Single(mailbox) => std::slice::from_ref(mailbox),
+            MailboxSelection::Multiple(mailboxes) => mailboxes,
+        }
+    }
+}
+
+// What the Fluvio record key is made of
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RecordKeyConfig {
+    Uid,
+    UidValidityUid,
+    MessageId,
+    ThreadRoot,
+    Sender,
+    // e.g. "{mailbox}/{sender}" - see README for the fields
+    Template(String),
+}
+
+impl TryFrom<String> for RecordKeyConfig {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "uid" => Ok(RecordKeyConfig::Uid),
+            "uidvalidity:uid" => Ok(RecordKeyConfig::UidValidityUid),
+            "message-id" => Ok(RecordKeyConfig::MessageId),
+            "thread-root" => Ok(RecordKeyConfig::ThreadRoot),
+            "sender" => Ok(RecordKeyConfig::Sender),
+            _ if value.contains('{') => Ok(RecordKeyConfig::Template(value)),
+            _ => Err(format!(
+                "Invalid record_key {} - expected uid, uidvalidity:uid, message-id, thread-root, sender or a template",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    #[default]
+    Implicit,
+    Starttls,
+    None,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    pub jitter: f64,
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: 10,
+        }
+    }
 }
 
 fn default_idle() -> u64 {
     300
 }
+
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_fetch_batch_size() -> usize {
+    100
+}
+
+// Sieve-like rule evaluated against every fetched message
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    // Match when any instead of all of the conditions match
+    #[serde(default)]
+    pub any: bool,
+    #[serde(default)]
+    pub conditions: Vec<RuleCondition>,
+    pub actions: Vec<RuleActionConfig>,
+    // Do not evaluate the following rules once this one matched
+    #[serde(default)]
+    pub stop: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleCondition {
+    Header { name: String, regex: String },
+    FromDomain(String),
+    ToDomain(String),
+    SizeOver(u32),
+    SizeUnder(u32),
+    Flag(String),
+    DkimAuthenticated(bool),
+    // e.g. method: dmarc, result: fail - requires mode_auth_results
+    AuthResult { method: String, result: String },
+    HasAttachment(bool),
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleActionConfig {
+    Move(String),
+    Copy(String),
+    Flag(Vec<String>),
+    Tag(String),
+    SkipProduce,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock_imap::config;
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let config = config(
+            993,
+            true,
+            serde_json::json!({
+                "password": "hunter2",
+                "auth": {
+                    "mechanism": "oauthbearer",
+                    "access_token": "access-1",
+                    "refresh_token": "refresh-1",
+                    "token_endpoint": "https://oauth2.example.com/token",
+                    "client_id": "client",
+                    "client_secret": "client-secret-1"
+                }
+            }),
+        );
+        let debug = format!("{:?}", config);
+        for secret in ["hunter2", "access-1", "refresh-1", "client-secret-1"] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
+        }
+        assert!(debug.contains("https://oauth2.example.com/token"));
+        assert_eq!(config.password.unwrap().expose(), "hunter2");
+    }
+}
@@ -0,0 +1,525 @@
+use crate::config::{ImapConfig, TlsMode};
+use crate::error::ImapError;
+use crate::util::hex_encode;
+use anyhow::{anyhow, bail, Context as _, Result};
+use async_imap::Client as AsyncImapClient;
+use async_native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use async_std::io::{BufReadExt, BufReader, Read, Write, WriteExt};
+use async_std::net::TcpStream;
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Transport to the IMAP server - TLS (implicit or upgraded via STARTTLS) or plaintext
+#[derive(Debug)]
+pub(crate) enum ImapStream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl Read for ImapStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for ImapStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ImapStream::Tls(stream) => Pin::new(stream).poll_close(cx),
+            ImapStream::Plain(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+// Establish the transport according to tls_mode, ready for login
+pub(crate) async fn connect(config: &ImapConfig) -> Result<AsyncImapClient<ImapStream>> {
+    let address = format!("{}:{}", config.host, config.port);
+    let tcp_stream = TcpStream::connect(&address)
+        .await
+        .map_err(|source| ImapError::Connect { address, source })?;
+
+    let stream = match config.tls_mode {
+        TlsMode::Implicit => {
+            debug!("TCP TLS Connect");
+            ImapStream::Tls(tls_connect(config, tcp_stream).await?)
+        }
+        TlsMode::Starttls => {
+            debug!("TCP STARTTLS Connect");
+            let tcp_stream = starttls(config, tcp_stream)
+                .await
+                .map_err(|e| tls_error(config, e, true))?;
+            ImapStream::Tls(tls_connect(config, tcp_stream).await?)
+        }
+        TlsMode::None => {
+            warn!("INSECURE: Connecting without TLS - credentials are sent in plaintext.");
+            ImapStream::Plain(tcp_stream)
+        }
+    };
+
+    Ok(AsyncImapClient::new(stream))
+}
+
+// Fail early upon unreadable certificate files or malformed pins instead of upon every reconnect
+pub(crate) fn check_tls_config(config: &ImapConfig) -> Result<()> {
+    if config.tls_mode != TlsMode::None {
+        tls_connector(config)?;
+    }
+    for pin in config
+        .tls_pin_cert_sha256
+        .iter()
+        .chain(config.tls_pin_spki_sha256.iter())
+    {
+        decode_pin(pin)?;
+    }
+    Ok(())
+}
+
+fn tls_connector(config: &ImapConfig) -> Result<TlsConnector> {
+    let mut connector = TlsConnector::new()
+        .use_sni(true)
+        .danger_accept_invalid_certs(config.dangerous_cert);
+
+    if let Some(ca_bundle) = &config.tls_ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .with_context(|| format!("Failed to read tls_ca_bundle {}", ca_bundle))?;
+        for certificate in pem_certificates(&pem)? {
+            connector = connector.add_root_certificate(certificate);
+        }
+    }
+
+    match (&config.tls_client_cert, &config.tls_client_key) {
+        (Some(cert), Some(key)) => {
+            let cert = std::fs::read(cert)
+                .with_context(|| format!("Failed to read tls_client_cert {}", cert))?;
+            let key = std::fs::read(key)
+                .with_context(|| format!("Failed to read tls_client_key {}", key))?;
+            connector = connector.identity(Identity::from_pkcs8(&cert, &key)?);
+        }
+        (None, None) => {}
+        _ => bail!("tls_client_cert and tls_client_key must be set together"),
+    }
+
+    Ok(connector)
+}
+
+async fn tls_connect(config: &ImapConfig, tcp_stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let connector = tls_connector(config).map_err(|e| tls_error(config, e, false))?;
+    let tls_stream = connector
+        .connect(config.host.clone(), tcp_stream)
+        .await
+        .map_err(|e| {
+            let retryable = !certificate_rejected(&e);
+            tls_error(config, e.into(), retryable)
+        })?;
+    // A certificate not matching the pins does not change by reconnecting
+    verify_pins(config, &tls_stream).map_err(|e| tls_error(config, e, false))?;
+    Ok(tls_stream)
+}
+
+// Verification failures e.g. an untrusted or expired certificate or a hostname mismatch do not go away by reconnecting.
+// native-tls only tells them apart from network & protocol failures by the message of the platform TLS library.
+fn certificate_rejected(err: &async_native_tls::Error) -> bool {
+    let message = err.to_string().to_ascii_lowercase();
+    [
+        "certificate",
+        "verify",
+        "trust",
+        "hostname",
+        "principal name",
+    ]
+    .iter()
+    .any(|needle| message.contains(needle))
+}
+
+// Failures classified already e.g. the STARTTLS refusal are kept as they are
+fn tls_error(config: &ImapConfig, err: anyhow::Error, retryable: bool) -> anyhow::Error {
+    if err.is::<ImapError>() {
+        return err;
+    }
+    ImapError::Tls {
+        host: config.host.clone(),
+        reason: format!("{:#}", err),
+        retryable,
+    }
+    .into()
+}
+
+// Split a PEM bundle into the individual certificates
+fn pem_certificates(pem: &[u8]) -> Result<Vec<Certificate>> {
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let pem = String::from_utf8_lossy(pem);
+    let mut certificates = vec![];
+    let mut rest = pem.as_ref();
+    while let Some(begin) = rest.find("-----BEGIN CERTIFICATE-----") {
+        let end = match rest[begin..].find(END) {
+            Some(end) => begin + end + END.len(),
+            None => bail!("Unterminated certificate in tls_ca_bundle"),
+        };
+        certificates.push(Certificate::from_pem(rest[begin..end].as_bytes())?);
+        rest = &rest[end..];
+    }
+
+    if certificates.is_empty() {
+        bail!("No certificates found in tls_ca_bundle");
+    }
+    Ok(certificates)
+}
+
+// Check the server certificate or its SubjectPublicKeyInfo against the configured SHA-256 pins
+fn verify_pins(config: &ImapConfig, tls_stream: &TlsStream<TcpStream>) -> Result<()> {
+    if config.tls_pin_cert_sha256.is_empty() && config.tls_pin_spki_sha256.is_empty() {
+        return Ok(());
+    }
+
+    let certificate = tls_stream
+        .peer_certificate()?
+        .ok_or_else(|| anyhow!("Server presented no certificate to check the pins against"))?;
+    match_pins(config, &certificate.to_der()?)
+}
+
+fn match_pins(config: &ImapConfig, cert_der: &[u8]) -> Result<()> {
+    let cert_hash = Sha256::digest(cert_der);
+    for pin in config.tls_pin_cert_sha256.iter() {
+        if decode_pin(pin)? == cert_hash.as_slice() {
+            debug!("Server certificate matched pin {}", pin);
+            return Ok(());
+        }
+    }
+
+    if !config.tls_pin_spki_sha256.is_empty() {
+        let spki_hash = Sha256::digest(spki_der(cert_der)?);
+        for pin in config.tls_pin_spki_sha256.iter() {
+            if decode_pin(pin)? == spki_hash.as_slice() {
+                debug!("Server public key matched pin {}", pin);
+                return Ok(());
+            }
+        }
+    }
+
+    bail!(
+        "Server certificate does not match any of the configured pins - certificate SHA-256 {}",
+        hex_encode(&cert_hash)
+    )
+}
+
+// Pins are accepted as hex (optionally colon separated) or base64, optionally prefixed with sha256/
+fn decode_pin(pin: &str) -> Result<Vec<u8>> {
+    let pin = pin.trim();
+    let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+
+    let hex = pin.replace(':', "");
+    let decoded = if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()?
+    } else {
+        BASE64_STANDARD
+            .decode(pin)
+            .with_context(|| format!("Pin {} is neither hex nor base64", pin))?
+    };
+
+    if decoded.len() != 32 {
+        bail!("Pin {} is not a SHA-256 digest", pin);
+    }
+    Ok(decoded)
+}
+
+// Minimal DER walk to the SubjectPublicKeyInfo of an X.509 certificate - RFC 5280 4.1
+fn spki_der(cert_der: &[u8]) -> Result<&[u8]> {
+    let (_, certificate, _, _) = der_next(cert_der)?;
+    let (_, tbs_certificate, _, _) = der_next(certificate)?;
+
+    let mut rest = tbs_certificate;
+    // version is an optional explicitly tagged [0]
+    if rest.first() == Some(&0xa0) {
+        rest = der_next(rest)?.3;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_next(rest)?.3;
+    }
+
+    let (tag, _, spki, _) = der_next(rest)?;
+    if tag != 0x30 {
+        bail!("Malformed certificate - SubjectPublicKeyInfo is not a SEQUENCE");
+    }
+    Ok(spki)
+}
+
+// Returns (tag, content, whole element, remaining input)
+fn der_next(input: &[u8]) -> Result<(u8, &[u8], &[u8], &[u8])> {
+    let malformed = || anyhow!("Malformed DER in server certificate");
+
+    let tag = *input.first().ok_or_else(malformed)?;
+    let first_len = *input.get(1).ok_or_else(malformed)?;
+
+    let (header_len, content_len) = if first_len < 0x80 {
+        (2, first_len as usize)
+    } else {
+        let len_bytes = (first_len & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 {
+            return Err(malformed());
+        }
+        let len = input
+            .get(2..2 + len_bytes)
+            .ok_or_else(malformed)?
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (2 + len_bytes, len)
+    };
+
+    let end = header_len
+        .checked_add(content_len)
+        .filter(|end| *end <= input.len())
+        .ok_or_else(malformed)?;
+
+    Ok((tag, &input[header_len..end], &input[..end], &input[end..]))
+}
+
+// Speak just enough IMAP over plaintext to upgrade the connection - RFC 3501 6.2.1
+async fn starttls(config: &ImapConfig, tcp_stream: TcpStream) -> Result<TcpStream> {
+    let mut reader = BufReader::new(&tcp_stream);
+    let mut writer = &tcp_stream;
+
+    let greeting = read_line(&mut reader).await?;
+    if !greeting.starts_with("* OK") {
+        bail!(
+            "Unexpected greeting before STARTTLS: {}",
+            greeting.trim_end()
+        );
+    }
+
+    writer.write_all(b"S0 CAPABILITY\r\n").await?;
+    let capabilities = read_tagged(&mut reader, "S0").await?;
+    let offers_starttls = capabilities
+        .iter()
+        .filter(|line| line.to_ascii_uppercase().starts_with("* CAPABILITY"))
+        .flat_map(|line| line.split_ascii_whitespace())
+        .any(|capability| capability.eq_ignore_ascii_case("STARTTLS"));
+
+    if !offers_starttls {
+        return Err(ImapError::Tls {
+            host: config.host.clone(),
+            reason: "Server does not offer STARTTLS - Refusing to login over plaintext."
+                .to_string(),
+            retryable: false,
+        }
+        .into());
+    }
+
+    writer.write_all(b"S1 STARTTLS\r\n").await?;
+    read_tagged(&mut reader, "S1").await?;
+
+    // Anything sent before the TLS handshake would be injected into the secure session
+    if !reader.buffer().is_empty() {
+        bail!("Server sent unexpected data after STARTTLS response.");
+    }
+    drop(reader);
+
+    Ok(tcp_stream)
+}
+
+async fn read_line(reader: &mut BufReader<&TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        bail!("Connection closed by server during STARTTLS negotiation.");
+    }
+    trace!("STARTTLS S: {}", line.trim_end());
+    Ok(line)
+}
+
+// Read until the tagged completion and return the untagged lines seen in between
+async fn read_tagged(reader: &mut BufReader<&TcpStream>, tag: &str) -> Result<Vec<String>> {
+    let mut untagged = vec![];
+    loop {
+        let line = read_line(reader).await?;
+        if let Some(status) = line.strip_prefix(tag) {
+            if status.trim_start().to_ascii_uppercase().starts_with("OK") {
+                return Ok(untagged);
+            }
+            bail!("STARTTLS negotiation failed: {}", line.trim_end());
+        }
+        untagged.push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_imap::MockImapServer;
+
+    fn certificate() -> rcgen::CertifiedKey {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap()
+    }
+
+    fn pinned(cert_pins: &[String], spki_pins: &[String]) -> ImapConfig {
+        crate::mock_imap::config(
+            993,
+            true,
+            serde_json::json!({
+                "tls_pin_cert_sha256": cert_pins,
+                "tls_pin_spki_sha256": spki_pins,
+            }),
+        )
+    }
+
+    #[test]
+    fn spki_of_generated_certificate() {
+        let rcgen::CertifiedKey { cert, key_pair } = certificate();
+        assert_eq!(spki_der(cert.der()).unwrap(), key_pair.public_key_der());
+    }
+
+    #[test]
+    fn truncated_or_overlong_der_fails() {
+        let rcgen::CertifiedKey { cert, .. } = certificate();
+        for len in 0..cert.der().len() {
+            assert!(
+                spki_der(&cert.der()[..len]).is_err(),
+                "Truncated to {}",
+                len
+            );
+        }
+
+        assert!(der_next(&[]).is_err());
+        assert!(der_next(&[0x30]).is_err());
+        assert!(der_next(&[0x30, 0x05, 0x00]).is_err());
+        // Indefinite length & more length bytes than we take
+        assert!(der_next(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(der_next(&[0x30, 0x85, 0x01, 0x02, 0x03, 0x04, 0x05]).is_err());
+        assert!(der_next(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00]).is_err());
+        assert!(der_next(&[0x30, 0x82, 0x01]).is_err());
+
+        // The certificate claiming to be longer than it is
+        let mut overlong = cert.der().to_vec();
+        overlong[2] += 1;
+        assert!(spki_der(&overlong).is_err());
+    }
+
+    #[test]
+    fn matches_hex_and_base64_pins() {
+        let rcgen::CertifiedKey { cert, key_pair } = certificate();
+        let cert_hash = Sha256::digest(cert.der());
+        let spki_hash = Sha256::digest(key_pair.public_key_der());
+
+        let colon_hex: Vec<String> = cert_hash.iter().map(|b| format!("{:02X}", b)).collect();
+        let cert_pins = [
+            hex_encode(&cert_hash),
+            colon_hex.join(":"),
+            BASE64_STANDARD.encode(cert_hash),
+            format!("sha256/{}", BASE64_STANDARD.encode(cert_hash)),
+        ];
+        for pin in cert_pins {
+            let config = pinned(&[pin.clone()], &[]);
+            assert!(match_pins(&config, cert.der()).is_ok(), "{}", pin);
+        }
+        for pin in [
+            hex_encode(&spki_hash),
+            format!("sha256/{}", BASE64_STANDARD.encode(spki_hash)),
+        ] {
+            let config = pinned(&[], &[pin.clone()]);
+            assert!(match_pins(&config, cert.der()).is_ok(), "{}", pin);
+        }
+
+        let other = certificate();
+        let config = pinned(&[hex_encode(&cert_hash)], &[hex_encode(&spki_hash)]);
+        assert!(match_pins(&config, other.cert.der()).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_pins() {
+        assert!(decode_pin("abcd").is_err());
+        assert!(decode_pin("not base64!").is_err());
+        // 64 characters but not hex - base64 of 48 bytes
+        assert!(decode_pin(&"zz".repeat(32)).is_err());
+        assert!(decode_pin(&format!("sha256/{}", "ab".repeat(32))).is_ok());
+    }
+
+    #[test]
+    fn splits_pem_bundle() {
+        let first = certificate().cert.pem();
+        let second = certificate().cert.pem();
+
+        let bundle = format!("# Bundle\n{}\n{}\ntrailing garbage\n", first, second);
+        assert_eq!(pem_certificates(bundle.as_bytes()).unwrap().len(), 2);
+
+        let truncated = format!("{}{}", first, &second[..second.len() / 2]);
+        assert!(pem_certificates(truncated.as_bytes()).is_err());
+        assert!(pem_certificates(b"garbage").is_err());
+    }
+
+    fn starttls_config(port: u16) -> ImapConfig {
+        crate::mock_imap::config(port, true, serde_json::json!({"tls_mode": "starttls"}))
+    }
+
+    #[async_std::test]
+    async fn upgrades_via_starttls() {
+        let acceptor = MockImapServer::self_signed_acceptor();
+        let server = MockImapServer::start_starttls("user", "secret", acceptor).await;
+        server.set_capabilities("IMAP4rev1 STARTTLS IDLE UIDPLUS MOVE");
+        let config = starttls_config(server.port);
+
+        let client = connect(&config).await.unwrap();
+        let mut session =
+            crate::auth::login(client, &config, &mut crate::auth::TokenProvider::new(None))
+                .await
+                .unwrap();
+        session.select("INBOX").await.unwrap();
+
+        // LOGIN only once upgraded
+        let commands = server.commands();
+        assert_eq!(commands[..2], ["CAPABILITY", "STARTTLS"]);
+        assert!(commands[2].starts_with("LOGIN"));
+    }
+
+    #[async_std::test]
+    async fn refuses_plaintext_without_starttls() {
+        let acceptor = MockImapServer::self_signed_acceptor();
+        let server = MockImapServer::start_starttls("user", "secret", acceptor).await;
+        let config = starttls_config(server.port);
+        assert!(!config.allow_insecure_plaintext);
+
+        let err = connect(&config).await.err().unwrap();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Tls { reason, .. }) => {
+                assert!(reason.contains("STARTTLS"));
+                assert!(!err.is_retryable());
+            }
+            _ => panic!("Expected a STARTTLS refusal: {:?}", err),
+        }
+        assert_eq!(server.commands(), ["CAPABILITY"]);
+    }
+}
@@ -0,0 +1,988 @@
+use crate::event::{DkimResult, DkimVerification};
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Don't let a message make us verify (and look up) an unbounded amount of signatures
+const MAX_SIGNATURES: usize = 5;
+
+// RFC 8301 3.2 - verifiers must not consider signatures with shorter RSA keys valid
+const MIN_RSA_BITS: usize = 1024;
+
+#[derive(Error, Debug)]
+pub(crate) enum LookupError {
+    #[error("No DKIM key record found")]
+    NotFound,
+    #[error("DKIM key lookup failed: {0}")]
+    Temporary(String),
+}
+
+// Public key lookup of `{selector}._domainkey.{domain}` - DNS in production, a static table in tests
+#[async_trait]
+pub(crate) trait DkimKeyResolver: Send + Sync {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError>;
+}
+
+pub(crate) struct DnsKeyResolver {
+    resolver: async_std_resolver::AsyncStdResolver,
+}
+
+impl DnsKeyResolver {
+    pub(crate) async fn from_system_conf() -> Result<Self> {
+        let resolver = async_std_resolver::resolver_from_system_conf().await?;
+        Ok(Self { resolver })
+    }
+}
+
+#[async_trait]
+impl DkimKeyResolver for DnsKeyResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+        match self.resolver.txt_lookup(name).await {
+            Ok(lookup) => Ok(lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect::<String>()
+                })
+                .collect()),
+            Err(e) if e.is_no_records_found() => Err(LookupError::NotFound),
+            Err(e) => Err(LookupError::Temporary(e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+#[derive(Debug)]
+struct Signature {
+    algorithm: Algorithm,
+    header_canon: Canonicalization,
+    body_canon: Canonicalization,
+    domain: String,
+    selector: String,
+    signed_headers: Vec<String>,
+    body_hash: Vec<u8>,
+    signature: Vec<u8>,
+    length: Option<usize>,
+    expiration: Option<u64>,
+}
+
+struct HeaderField<'a> {
+    name: String,
+    // The whole field including continuation lines and the trailing CRLF
+    raw: &'a [u8],
+}
+
+// Verify every DKIM-Signature of the full RFC 5322 message
+pub(crate) async fn verify_message(
+    resolver: &dyn DkimKeyResolver,
+    raw: &[u8],
+) -> Vec<DkimVerification> {
+    let message = to_crlf(raw);
+    let (headers, body) = split_message(&message);
+
+    let mut results = vec![];
+    for field in headers
+        .iter()
+        .rev()
+        .filter(|h| h.name.eq_ignore_ascii_case("DKIM-Signature"))
+        .take(MAX_SIGNATURES)
+    {
+        let result = verify_signature(resolver, &headers, body, field).await;
+        debug!("DKIM verification {:?}", &result);
+        results.push(result);
+    }
+    results
+}
+
+async fn verify_signature(
+    resolver: &dyn DkimKeyResolver,
+    headers: &[HeaderField<'_>],
+    body: &[u8],
+    field: &HeaderField<'_>,
+) -> DkimVerification {
+    let value = String::from_utf8_lossy(field_value(field.raw));
+    let signature = match parse_signature(&value) {
+        Ok(signature) => signature,
+        Err(reason) => return outcome(None, DkimResult::Permerror, Some(reason)),
+    };
+
+    if let Some(expiration) = signature.expiration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if now > expiration {
+            return outcome(
+                Some(&signature),
+                DkimResult::Fail,
+                Some("signature expired".to_string()),
+            );
+        }
+    }
+
+    // Body hash first - no need to look up the key for a modified body
+    let canon_body = canonicalize_body(signature.body_canon, body);
+    let canon_body = match signature.length {
+        Some(length) if length <= canon_body.len() => &canon_body[..length],
+        Some(_) => {
+            return outcome(
+                Some(&signature),
+                DkimResult::Permerror,
+                Some("l= exceeds the body length".to_string()),
+            )
+        }
+        None => &canon_body[..],
+    };
+    if Sha256::digest(canon_body).as_slice() != signature.body_hash.as_slice() {
+        return outcome(
+            Some(&signature),
+            DkimResult::Fail,
+            Some("body hash did not verify".to_string()),
+        );
+    }
+
+    let key = match lookup_key(resolver, &signature).await {
+        Ok(key) => key,
+        Err((result, reason)) => return outcome(Some(&signature), result, Some(reason)),
+    };
+
+    let signed_data = signed_header_data(&signature, headers, field);
+    let verified = match key {
+        PublicKey::Rsa(key) => verify_rsa(&key, &signed_data, &signature.signature),
+        PublicKey::Ed25519(key) => verify_ed25519(&key, &signed_data, &signature.signature),
+    };
+    match verified {
+        true => outcome(Some(&signature), DkimResult::Pass, None),
+        false => outcome(
+            Some(&signature),
+            DkimResult::Fail,
+            Some("signature did not verify".to_string()),
+        ),
+    }
+}
+
+fn outcome(
+    signature: Option<&Signature>,
+    result: DkimResult,
+    reason: Option<String>,
+) -> DkimVerification {
+    DkimVerification {
+        domain: signature.map(|s| s.domain.clone()),
+        selector: signature.map(|s| s.selector.clone()),
+        algorithm: signature.map(|s| match s.algorithm {
+            Algorithm::RsaSha256 => "rsa-sha256".to_string(),
+            Algorithm::Ed25519Sha256 => "ed25519-sha256".to_string(),
+        }),
+        result,
+        reason,
+    }
+}
+
+// tag=value list of RFC 6376 3.2
+fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+fn parse_signature(value: &str) -> Result<Signature, String> {
+    let tags = parse_tags(value);
+    let tag = |name: &str| {
+        tags.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let required = |name: &str| tag(name).ok_or_else(|| format!("missing {}= tag", name));
+
+    if required("v")? != "1" {
+        return Err("unsupported version".to_string());
+    }
+    let algorithm = match required("a")? {
+        "rsa-sha256" => Algorithm::RsaSha256,
+        "ed25519-sha256" => Algorithm::Ed25519Sha256,
+        other => return Err(format!("unsupported algorithm {}", other)),
+    };
+    let (header_canon, body_canon) = match tag("c") {
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+        Some(c) => {
+            let mut parts = c.splitn(2, '/');
+            let header = parse_canonicalization(parts.next().unwrap_or("simple"))?;
+            let body = parse_canonicalization(parts.next().unwrap_or("simple"))?;
+            (header, body)
+        }
+    };
+    let domain = required("d")?.to_lowercase();
+    let selector = required("s")?.to_lowercase();
+    let signed_headers: Vec<String> = required("h")?
+        .split(':')
+        .map(|h| h.trim().to_lowercase())
+        .filter(|h| !h.is_empty())
+        .collect();
+    if !signed_headers.iter().any(|h| h == "from") {
+        return Err("From is not signed".to_string());
+    }
+    if let Some(identity) = tag("i") {
+        let identity_domain = identity
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain)) {
+            return Err("i= is not within d=".to_string());
+        }
+    }
+    let body_hash = BASE64
+        .decode(strip_whitespace(required("bh")?))
+        .map_err(|e| format!("invalid bh= {}", e))?;
+    let signature = BASE64
+        .decode(strip_whitespace(required("b")?))
+        .map_err(|e| format!("invalid b= {}", e))?;
+    let length = match tag("l") {
+        Some(l) => Some(l.parse().map_err(|_| "invalid l=".to_string())?),
+        None => None,
+    };
+    let expiration = match tag("x") {
+        Some(x) => Some(x.parse().map_err(|_| "invalid x=".to_string())?),
+        None => None,
+    };
+
+    Ok(Signature {
+        algorithm,
+        header_canon,
+        body_canon,
+        domain,
+        selector,
+        signed_headers,
+        body_hash,
+        signature,
+        length,
+        expiration,
+    })
+}
+
+fn parse_canonicalization(value: &str) -> Result<Canonicalization, String> {
+    match value {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        other => Err(format!("unsupported canonicalization {}", other)),
+    }
+}
+
+enum PublicKey {
+    Rsa(rsa::RsaPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+async fn lookup_key(
+    resolver: &dyn DkimKeyResolver,
+    signature: &Signature,
+) -> Result<PublicKey, (DkimResult, String)> {
+    let name = format!("{}._domainkey.{}", signature.selector, signature.domain);
+    let records = match resolver.lookup_txt(&name).await {
+        Ok(records) => records,
+        Err(LookupError::NotFound) => {
+            return Err((DkimResult::Permerror, format!("no key record at {}", name)))
+        }
+        Err(e) => return Err((DkimResult::Temperror, e.to_string())),
+    };
+    // Other TXT records (e.g. SPF) may share the name - the first key record counts
+    let mut version_mismatch = false;
+    let record = records
+        .iter()
+        .map(|record| parse_tags(record))
+        .find(|record| {
+            let version = tag(record, "v");
+            let key = tag(record, "p").is_some();
+            version_mismatch |= key && version.is_some_and(|v| v != "DKIM1");
+            key && version.map_or(true, |v| v == "DKIM1")
+        });
+    let record = match record {
+        Some(record) => record,
+        None if version_mismatch => {
+            return Err((
+                DkimResult::Permerror,
+                "invalid key record version".to_string(),
+            ))
+        }
+        None => return Err((DkimResult::Permerror, format!("no key record at {}", name))),
+    };
+    let tag = |name: &str| tag(&record, name);
+
+    let key_type = tag("k").unwrap_or("rsa");
+    let public_key = strip_whitespace(tag("p").unwrap_or_default());
+    if public_key.is_empty() {
+        return Err((DkimResult::Permerror, "key revoked".to_string()));
+    }
+    let public_key = BASE64
+        .decode(public_key)
+        .map_err(|e| (DkimResult::Permerror, format!("invalid key p= {}", e)))?;
+
+    match (key_type, signature.algorithm) {
+        ("rsa", Algorithm::RsaSha256) => {
+            use rsa::pkcs1::DecodeRsaPublicKey;
+            use rsa::pkcs8::DecodePublicKey;
+            use rsa::traits::PublicKeyParts;
+            // SubjectPublicKeyInfo per RFC 6376 - some publish the bare PKCS#1 RSAPublicKey
+            let key = rsa::RsaPublicKey::from_public_key_der(&public_key)
+                .or_else(|_| rsa::RsaPublicKey::from_pkcs1_der(&public_key))
+                .map_err(|e| (DkimResult::Permerror, format!("invalid RSA key {}", e)))?;
+            if key.n().bits() < MIN_RSA_BITS {
+                return Err((
+                    DkimResult::Permerror,
+                    format!("RSA key of {} bits is too short", key.n().bits()),
+                ));
+            }
+            Ok(PublicKey::Rsa(key))
+        }
+        ("ed25519", Algorithm::Ed25519Sha256) => {
+            let bytes: [u8; 32] = public_key.as_slice().try_into().map_err(|_| {
+                (
+                    DkimResult::Permerror,
+                    "invalid Ed25519 key length".to_string(),
+                )
+            })?;
+            ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .map(PublicKey::Ed25519)
+                .map_err(|e| (DkimResult::Permerror, format!("invalid Ed25519 key {}", e)))
+        }
+        (key_type, _) => Err((
+            DkimResult::Permerror,
+            format!("key type {} does not match the algorithm", key_type),
+        )),
+    }
+}
+
+fn verify_rsa(key: &rsa::RsaPublicKey, data: &[u8], signature: &[u8]) -> bool {
+    use rsa::signature::Verifier;
+    let key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key.clone());
+    match rsa::pkcs1v15::Signature::try_from(signature) {
+        Ok(signature) => key.verify(data, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// RFC 8463 - Ed25519 signs the SHA-256 hash of the header data rather than the data itself
+fn verify_ed25519(key: &ed25519_dalek::VerifyingKey, data: &[u8], signature: &[u8]) -> bool {
+    use ed25519_dalek::Verifier;
+    match ed25519_dalek::Signature::from_slice(signature) {
+        Ok(signature) => key.verify(&Sha256::digest(data), &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// The signed header fields followed by the DKIM-Signature itself with an empty b=
+fn signed_header_data(
+    signature: &Signature,
+    headers: &[HeaderField<'_>],
+    field: &HeaderField<'_>,
+) -> Vec<u8> {
+    let mut data = vec![];
+    let mut used = vec![false; headers.len()];
+
+    // Multiple instances of a header are signed from the bottom up
+    for name in signature.signed_headers.iter() {
+        let found = headers
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, h)| !used[*i] && h.name.eq_ignore_ascii_case(name));
+        if let Some((i, header)) = found {
+            used[i] = true;
+            data.extend(canonicalize_header(signature.header_canon, header.raw));
+        }
+    }
+
+    let without_b = strip_b_tag(field.raw);
+    let mut canon = canonicalize_header(signature.header_canon, &without_b);
+    if canon.ends_with(b"\r\n") {
+        canon.truncate(canon.len() - 2);
+    }
+    data.extend(canon);
+    data
+}
+
+// Empty the value of the b= tag keeping everything else byte for byte
+fn strip_b_tag(raw: &[u8]) -> Vec<u8> {
+    let colon = match raw.iter().position(|b| *b == b':') {
+        Some(colon) => colon,
+        None => return raw.to_vec(),
+    };
+    let (name, value) = (&raw[..=colon], &raw[colon + 1..]);
+    let tags: Vec<&[u8]> = value
+        .split(|b| *b == b';')
+        .map(|tag| match tag.iter().position(|b| *b == b'=') {
+            Some(eq) if trim_fws(&tag[..eq]) == b"b" => &tag[..=eq],
+            _ => tag,
+        })
+        .collect();
+    let mut stripped = name.to_vec();
+    stripped.extend(tags.join(&b';'));
+    stripped
+}
+
+fn trim_fws(input: &[u8]) -> &[u8] {
+    let is_fws = |b: &u8| is_wsp(*b) || *b == b'\r' || *b == b'\n';
+    let start = input.iter().position(|b| !is_fws(b)).unwrap_or(input.len());
+    let end = input
+        .iter()
+        .rposition(|b| !is_fws(b))
+        .map_or(start, |end| end + 1);
+    &input[start..end]
+}
+
+fn field_value(raw: &[u8]) -> &[u8] {
+    match raw.iter().position(|b| *b == b':') {
+        Some(colon) => &raw[colon + 1..],
+        None => raw,
+    }
+}
+
+// IMAP servers should hand out CRLF but bare LF is not unheard of
+fn to_crlf(raw: &[u8]) -> Cow<'_, [u8]> {
+    let has_bare_lf = raw
+        .iter()
+        .enumerate()
+        .any(|(i, b)| *b == b'\n' && (i == 0 || raw[i - 1] != b'\r'));
+    if !has_bare_lf {
+        return Cow::Borrowed(raw);
+    }
+    let mut out = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for (i, b) in raw.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(*b);
+    }
+    Cow::Owned(out)
+}
+
+fn split_message(message: &[u8]) -> (Vec<HeaderField<'_>>, &[u8]) {
+    let mut headers: Vec<HeaderField> = vec![];
+    let mut field_start = 0;
+    let mut pos = 0;
+    while pos < message.len() {
+        let line_end = match message[pos..].windows(2).position(|w| w == b"\r\n") {
+            Some(end) => pos + end + 2,
+            None => message.len(),
+        };
+        let line = &message[pos..line_end];
+        if line == b"\r\n" {
+            return (headers, &message[line_end..]);
+        }
+        match (is_wsp(line[0]), headers.last_mut()) {
+            // Continuation - extend the previous field over this line
+            (true, Some(last)) => last.raw = &message[field_start..line_end],
+            _ => {
+                let name = match line.iter().position(|b| *b == b':') {
+                    Some(colon) => String::from_utf8_lossy(&line[..colon]).trim().to_string(),
+                    None => String::new(),
+                };
+                field_start = pos;
+                headers.push(HeaderField { name, raw: line });
+            }
+        }
+        pos = line_end;
+    }
+    (headers, &[])
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+// Reduce every run of whitespace to a single space
+fn compress_wsp(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut in_wsp = false;
+    for b in input {
+        if is_wsp(*b) {
+            if !in_wsp {
+                out.push(b' ');
+            }
+            in_wsp = true;
+        } else {
+            out.push(*b);
+            in_wsp = false;
+        }
+    }
+    out
+}
+
+fn trim_wsp(mut input: &[u8]) -> &[u8] {
+    while let Some((first, rest)) = input.split_first() {
+        if !is_wsp(*first) {
+            break;
+        }
+        input = rest;
+    }
+    while let Some((last, rest)) = input.split_last() {
+        if !is_wsp(*last) {
+            break;
+        }
+        input = rest;
+    }
+    input
+}
+
+// RFC 6376 3.4.1 & 3.4.2
+fn canonicalize_header(canon: Canonicalization, raw: &[u8]) -> Vec<u8> {
+    match canon {
+        Canonicalization::Simple => raw.to_vec(),
+        Canonicalization::Relaxed => {
+            let (name, value) = match raw.iter().position(|b| *b == b':') {
+                Some(colon) => (&raw[..colon], &raw[colon + 1..]),
+                None => (raw, &[][..]),
+            };
+            let unfolded: Vec<u8> = value
+                .iter()
+                .filter(|b| **b != b'\r' && **b != b'\n')
+                .copied()
+                .collect();
+            let mut out = trim_wsp(name).to_ascii_lowercase();
+            out.push(b':');
+            out.extend(trim_wsp(&compress_wsp(&unfolded)));
+            out.extend(b"\r\n");
+            out
+        }
+    }
+}
+
+// RFC 6376 3.4.3 & 3.4.4
+fn canonicalize_body(canon: Canonicalization, body: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Cow<[u8]>> = body
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .map(|line| match canon {
+            Canonicalization::Simple => Cow::Borrowed(line),
+            Canonicalization::Relaxed => {
+                let mut line = compress_wsp(line);
+                if line.last() == Some(&b' ') {
+                    line.pop();
+                }
+                Cow::Owned(line)
+            }
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        return match canon {
+            Canonicalization::Simple => b"\r\n".to_vec(),
+            Canonicalization::Relaxed => vec![],
+        };
+    }
+    let mut out = Vec::with_capacity(body.len());
+    for line in lines {
+        out.extend(line.iter());
+        out.extend(b"\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // In-memory key table in place of DNS - one TXT record per line
+    struct StaticKeyResolver {
+        keys: HashMap<String, String>,
+    }
+
+    #[async_trait]
+    impl DkimKeyResolver for StaticKeyResolver {
+        async fn lookup_txt(&self, name: &str) -> Result<Vec<String>, LookupError> {
+            match self.keys.get(name) {
+                Some(records) => Ok(records.lines().map(|r| r.to_string()).collect()),
+                None => Err(LookupError::NotFound),
+            }
+        }
+    }
+
+    const MESSAGE: &str = "From: Alice <alice@example.com>\r\n\
+        To: bob@example.org\r\n\
+        Subject: Hello  there\r\n\
+        \tfolded\r\n\
+        Date: Fri, 5 Jul 2024 02:26:27 +0000\r\n\
+        \r\n\
+        Hi Bob,  \r\n\
+        \r\n\
+        a DKIM test.\r\n\
+        \r\n\
+        \r\n";
+
+    fn ed25519_key() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    fn ed25519_record(key: &ed25519_dalek::SigningKey) -> String {
+        format!(
+            "v=DKIM1; k=ed25519; p={}",
+            BASE64.encode(key.verifying_key().as_bytes())
+        )
+    }
+
+    // Sign the message the way a sending MTA would, prepending the DKIM-Signature
+    fn sign(message: &str, c: &str, sign_data: impl Fn(&[u8]) -> Vec<u8>, a: &str) -> String {
+        let header_canon = match c.split('/').next() {
+            Some("relaxed") => Canonicalization::Relaxed,
+            _ => Canonicalization::Simple,
+        };
+        let body_canon = match c.split('/').nth(1) {
+            Some("relaxed") => Canonicalization::Relaxed,
+            _ => Canonicalization::Simple,
+        };
+        let (headers, body) = split_message(message.as_bytes());
+        let bh = BASE64.encode(Sha256::digest(canonicalize_body(body_canon, body)));
+        let unsigned = format!(
+            "DKIM-Signature: v=1; a={}; c={}; d=example.com; s=sel;\r\n\th=from:to:subject; bh={};\r\n\tb=\r\n",
+            a, c, bh
+        );
+
+        let mut data = vec![];
+        for name in ["from", "to", "subject"] {
+            let header = headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case(name))
+                .unwrap();
+            data.extend(canonicalize_header(header_canon, header.raw));
+        }
+        let mut canon = canonicalize_header(header_canon, unsigned.as_bytes());
+        canon.truncate(canon.len() - 2);
+        data.extend(canon);
+
+        let b = BASE64.encode(sign_data(&data));
+        format!(
+            "{}{}",
+            unsigned.replace("b=\r\n", &format!("b={}\r\n", b)),
+            message
+        )
+    }
+
+    fn sign_ed25519(message: &str, c: &str) -> String {
+        use ed25519_dalek::Signer;
+        let key = ed25519_key();
+        sign(
+            message,
+            c,
+            |data| key.sign(&Sha256::digest(data)).to_bytes().to_vec(),
+            "ed25519-sha256",
+        )
+    }
+
+    fn resolver(record: String) -> StaticKeyResolver {
+        StaticKeyResolver {
+            keys: HashMap::from([("sel._domainkey.example.com".to_string(), record)]),
+        }
+    }
+
+    #[test]
+    fn canonicalization_rfc6376_example() {
+        // RFC 6376 3.4.5
+        let message = b"A: X\r\nB : Y\t\r\n\tZ  \r\n\r\n C \r\nD \t E\r\n\r\n\r\n";
+        let (headers, body) = split_message(message);
+
+        let relaxed: Vec<u8> = headers
+            .iter()
+            .flat_map(|h| canonicalize_header(Canonicalization::Relaxed, h.raw))
+            .collect();
+        assert_eq!(relaxed, b"a:X\r\nb:Y Z\r\n");
+        assert_eq!(
+            canonicalize_body(Canonicalization::Relaxed, body),
+            b" C\r\nD E\r\n"
+        );
+
+        let simple: Vec<u8> = headers
+            .iter()
+            .flat_map(|h| canonicalize_header(Canonicalization::Simple, h.raw))
+            .collect();
+        assert_eq!(simple, b"A: X\r\nB : Y\t\r\n\tZ  \r\n");
+        assert_eq!(
+            canonicalize_body(Canonicalization::Simple, body),
+            b" C \r\nD \t E\r\n"
+        );
+    }
+
+    #[test]
+    fn empty_body() {
+        assert_eq!(canonicalize_body(Canonicalization::Simple, b""), b"\r\n");
+        assert_eq!(
+            canonicalize_body(Canonicalization::Relaxed, b"\r\n\r\n"),
+            b""
+        );
+    }
+
+    #[async_std::test]
+    async fn ed25519_pass() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        for c in ["simple/simple", "relaxed/relaxed", "relaxed/simple"] {
+            let signed = sign_ed25519(MESSAGE, c);
+            let results = verify_message(&resolver, signed.as_bytes()).await;
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].result,
+                DkimResult::Pass,
+                "{} {:?}",
+                c,
+                results[0]
+            );
+            assert_eq!(results[0].domain.as_deref(), Some("example.com"));
+            assert_eq!(results[0].selector.as_deref(), Some("sel"));
+        }
+    }
+
+    #[async_std::test]
+    async fn bare_lf_message() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        let signed = sign_ed25519(MESSAGE, "relaxed/relaxed").replace("\r\n", "\n");
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Pass);
+    }
+
+    #[async_std::test]
+    async fn rsa_pass() {
+        use rsa::pkcs8::EncodePublicKey;
+        use rsa::signature::{SignatureEncoding, Signer};
+
+        let private_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public_der = private_key.to_public_key().to_public_key_der().unwrap();
+        let resolver = resolver(format!(
+            "v=DKIM1; k=rsa; p={}",
+            BASE64.encode(public_der.as_bytes())
+        ));
+        let signing_key = rsa::pkcs1v15::SigningKey::<Sha256>::new(private_key);
+
+        let signed = sign(
+            MESSAGE,
+            "relaxed/relaxed",
+            |data| signing_key.sign(data).to_vec(),
+            "rsa-sha256",
+        );
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Pass, "{:?}", results[0]);
+        assert_eq!(results[0].algorithm.as_deref(), Some("rsa-sha256"));
+    }
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/dkim")
+            .join(name);
+        std::fs::read(path).unwrap()
+    }
+
+    #[async_std::test]
+    async fn rfc8463_example() {
+        // RFC 8463 Appendix A - the same message signed with Ed25519 and RSA
+        let resolver = StaticKeyResolver {
+            keys: HashMap::from([
+                (
+                    "brisbane._domainkey.football.example.com".to_string(),
+                    "v=DKIM1; k=ed25519; p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+                        .to_string(),
+                ),
+                (
+                    "test._domainkey.football.example.com".to_string(),
+                    "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDkHlOQoBTzWRiGs5V6NpP3idY6Wk08a5qhdR6wy5bdOKb2jLQiY/J16JYi0Qvx/byYzCNb3W91y3FutACDfzwQ/BC/e/8uBsCR+yz1Lxj+PL6lHvqMKrM3rG4hstT5QjvHO9PzoxZyVYLzBfO2EeC3Ip3G+2kryOTIKT+l/K4w3QIDAQAB"
+                        .to_string(),
+                ),
+            ]),
+        };
+        let results = verify_message(&resolver, &fixture("rfc8463.eml")).await;
+        assert_eq!(results.len(), 2);
+        for algorithm in ["ed25519-sha256", "rsa-sha256"] {
+            let result = results
+                .iter()
+                .find(|r| r.algorithm.as_deref() == Some(algorithm))
+                .unwrap();
+            assert_eq!(result.result, DkimResult::Pass, "{:?}", result);
+            assert_eq!(result.domain.as_deref(), Some("football.example.com"));
+        }
+    }
+
+    #[async_std::test]
+    async fn relaxed_simple_list_message() {
+        // Signed by a mailing list with c=relaxed/simple - trailing whitespace in the body must be kept
+        let resolver = StaticKeyResolver {
+            keys: HashMap::from([(
+                "ietf1._domainkey.ietf.org".to_string(),
+                "k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDNzNnjKTd5cczd2CDzHflCZuv1tMWYwd7zE+deoJ6s/fXR7/n9ZIBnDS5egt7HAHjNjZrmjcoRlfSsNxRJvUQFyYvaU1BT1s8R+mkPgSOqZ4t9HqAVjiczn2B9+dbjdNN+S/zvSyMMuSCSJDKKAXhBpDeQTpeY7/UdP9s6ws0yjQIDAQAB"
+                    .to_string(),
+            )]),
+        };
+        let message = fixture("relaxed_simple.eml");
+        let results = verify_message(&resolver, &message).await;
+        assert_eq!(results.len(), 2);
+        for result in results.iter() {
+            assert_eq!(result.result, DkimResult::Pass, "{:?}", result);
+        }
+
+        let message = String::from_utf8(message)
+            .unwrap()
+            .replace("no comments in response.  ", "no comments in response.");
+        let results = verify_message(&resolver, message.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Fail);
+    }
+
+    #[async_std::test]
+    async fn short_rsa_key() {
+        use rsa::pkcs8::EncodePublicKey;
+        use rsa::signature::{SignatureEncoding, Signer};
+
+        let private_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let public_der = private_key.to_public_key().to_public_key_der().unwrap();
+        let resolver = resolver(format!(
+            "v=DKIM1; k=rsa; p={}",
+            BASE64.encode(public_der.as_bytes())
+        ));
+        let signing_key = rsa::pkcs1v15::SigningKey::<Sha256>::new(private_key);
+
+        let signed = sign(
+            MESSAGE,
+            "relaxed/relaxed",
+            |data| signing_key.sign(data).to_vec(),
+            "rsa-sha256",
+        );
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Permerror);
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("RSA key of 512 bits is too short")
+        );
+    }
+
+    #[async_std::test]
+    async fn modified_body_fails() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        let signed = sign_ed25519(MESSAGE, "relaxed/relaxed").replace("a DKIM test", "a DKIM tset");
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Fail);
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("body hash did not verify")
+        );
+    }
+
+    #[async_std::test]
+    async fn modified_header_fails() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        let signed =
+            sign_ed25519(MESSAGE, "relaxed/relaxed").replace("bob@example.org", "eve@example.org");
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Fail);
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("signature did not verify")
+        );
+    }
+
+    #[async_std::test]
+    async fn relaxed_tolerates_whitespace() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        let signed = sign_ed25519(MESSAGE, "relaxed/relaxed")
+            .replace("Subject: Hello  there", "Subject:  Hello there ")
+            .replace("Hi Bob,  ", "Hi  Bob,");
+        let results = verify_message(&resolver, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Pass);
+    }
+
+    #[async_std::test]
+    async fn key_errors() {
+        let signed = sign_ed25519(MESSAGE, "simple/simple");
+
+        let missing = StaticKeyResolver {
+            keys: HashMap::new(),
+        };
+        let results = verify_message(&missing, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Permerror);
+
+        let revoked = resolver("v=DKIM1; k=ed25519; p=".to_string());
+        let results = verify_message(&revoked, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Permerror);
+        assert_eq!(results[0].reason.as_deref(), Some("key revoked"));
+
+        let other_key = ed25519_dalek::SigningKey::from_bytes(&[8u8; 32]);
+        let wrong = resolver(ed25519_record(&other_key));
+        let results = verify_message(&wrong, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Fail);
+    }
+
+    #[async_std::test]
+    async fn unsigned_message() {
+        let resolver = resolver(ed25519_record(&ed25519_key()));
+        let results = verify_message(&resolver, MESSAGE.as_bytes()).await;
+        assert!(results.is_empty());
+    }
+
+    #[async_std::test]
+    async fn key_record_among_other_txt_records() {
+        let signed = sign_ed25519(MESSAGE, "relaxed/relaxed");
+        let key = ed25519_record(&ed25519_key());
+
+        let with_spf = resolver(format!("v=spf1 -all\n{}", key));
+        let results = verify_message(&with_spf, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Pass, "{:?}", results[0]);
+
+        let other_version = resolver(format!("v=DKIM2; p=abc\n{}", key));
+        let results = verify_message(&other_version, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Pass, "{:?}", results[0]);
+
+        let only_spf = resolver("v=spf1 -all".to_string());
+        let results = verify_message(&only_spf, signed.as_bytes()).await;
+        assert_eq!(results[0].result, DkimResult::Permerror);
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("no key record at sel._domainkey.example.com")
+        );
+
+        let only_other_version = resolver("v=DKIM2; p=abc".to_string());
+        let results = verify_message(&only_other_version, signed.as_bytes()).await;
+        assert_eq!(
+            results[0].reason.as_deref(),
+            Some("invalid key record version")
+        );
+    }
+
+    #[test]
+    fn strip_b_tag_keeps_bytes() {
+        let raw = b"DKIM-Signature: v=1; z=Subject:caf\xe9;\r\n\t b =abc\r\n\tdef; bh=xyz\r\n";
+        assert_eq!(
+            strip_b_tag(raw),
+            b"DKIM-Signature: v=1; z=Subject:caf\xe9;\r\n\t b =; bh=xyz\r\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use async_imap::error::Error as AsyncImapError;
+use thiserror::Error;
+
+use std::io;
+
+// Failures of the IMAP session - the supervisor reconnects upon the retryable ones and stops upon the fatal ones
+#[derive(Error, Debug)]
+pub(crate) enum ImapError {
+    #[error("Failed to connect to {address}: {source}")]
+    Connect {
+        address: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("TLS with {host} failed: {reason}")]
+    Tls {
+        host: String,
+        reason: String,
+        retryable: bool,
+    },
+    #[error("Authentication of {user} failed: {source}")]
+    Auth {
+        user: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Listing mailboxes matching {pattern} failed: {source}")]
+    List {
+        pattern: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Failed to create mailbox {mailbox}: {source}")]
+    Create {
+        mailbox: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Failed to query the capabilities: {source}")]
+    Capabilities {
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Failed to enable {extension}: {source}")]
+    Enable {
+        extension: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Server does not support {capability} required for {required_for}")]
+    Capability {
+        capability: String,
+        required_for: String,
+    },
+    #[error("Mailboxes {mailbox} and {other} would both be produced to topic {topic}")]
+    MailboxTopic {
+        mailbox: String,
+        other: String,
+        topic: String,
+    },
+    #[error("Failed to select mailbox {mailbox}: {source}")]
+    Select {
+        mailbox: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Failed to get the status of mailbox {mailbox}: {source}")]
+    Status {
+        mailbox: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Waiting for new mail failed: {source}")]
+    Idle {
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("NOOP failed: {source}")]
+    Noop {
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Search in {mailbox} failed: {source}")]
+    Search {
+        mailbox: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Fetching UIDs {uids} of {mailbox} failed: {source}")]
+    Fetch {
+        mailbox: String,
+        uids: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Storing {query} on UIDs {uids} of {mailbox} failed: {source}")]
+    Store {
+        mailbox: String,
+        uids: String,
+        query: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Copying UIDs {uids} from {mailbox} to {destination} failed: {source}")]
+    Copy {
+        mailbox: String,
+        uids: String,
+        destination: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Moving UIDs {uids} from {mailbox} to {destination} failed: {source}")]
+    Move {
+        mailbox: String,
+        uids: String,
+        destination: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Expunging UIDs {uids} of {mailbox} failed: {source}")]
+    Expunge {
+        mailbox: String,
+        uids: String,
+        #[source]
+        source: AsyncImapError,
+    },
+    #[error("Failed to hand the record over to the producer: {reason}")]
+    Produce { reason: String },
+}
+
+impl ImapError {
+    // Whether reconnecting may help - rejected credentials, pins or mailboxes need the config fixed first
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            ImapError::Connect { source, .. } => source.kind() != io::ErrorKind::InvalidInput,
+            ImapError::Tls { retryable, .. } => *retryable,
+            ImapError::Capability { .. } | ImapError::MailboxTopic { .. } => false,
+            ImapError::Auth { source, .. }
+            | ImapError::Capabilities { source }
+            | ImapError::Enable { source, .. }
+            | ImapError::List { source, .. }
+            | ImapError::Create { source, .. }
+            | ImapError::Select { source, .. }
+            | ImapError::Status { source, .. }
+            | ImapError::Idle { source }
+            | ImapError::Noop { source } => connection_lost(source),
+            // e.g. NO upon a message expunged by another client in the meantime - a NO that persists
+            // runs out of the reconnect attempts as the session never gets through a full sync
+            ImapError::Search { source, .. } | ImapError::Fetch { source, .. } => {
+                connection_lost(source) || matches!(source, AsyncImapError::No(_))
+            }
+            // NO e.g. upon a missing or forbidden destination - retrying keeps failing the same way
+            ImapError::Store { source, .. }
+            | ImapError::Copy { source, .. }
+            | ImapError::Move { source, .. }
+            | ImapError::Expunge { source, .. } => connection_lost(source),
+            // The connector side is gone - there is nobody to reconnect for
+            ImapError::Produce { .. } => false,
+        }
+    }
+}
+
+fn connection_lost(err: &AsyncImapError) -> bool {
+    matches!(err, AsyncImapError::Io(_) | AsyncImapError::ConnectionLost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_retryable_and_fatal() {
+        let auth = |source| ImapError::Auth {
+            user: "user".to_string(),
+            source,
+        };
+        assert!(!auth(AsyncImapError::No("invalid credentials".to_string())).is_retryable());
+        assert!(auth(AsyncImapError::ConnectionLost).is_retryable());
+
+        let fetch = ImapError::Fetch {
+            mailbox: "INBOX".to_string(),
+            uids: "1:5".to_string(),
+            source: AsyncImapError::No("try again".to_string()),
+        };
+        assert!(fetch.is_retryable());
+        assert!(fetch
+            .to_string()
+            .starts_with("Fetching UIDs 1:5 of INBOX failed:"));
+
+        let move_to = |source| ImapError::Move {
+            mailbox: "INBOX".to_string(),
+            uids: "1".to_string(),
+            destination: "Missing".to_string(),
+            source,
+        };
+        assert!(!move_to(AsyncImapError::No("no such mailbox".to_string())).is_retryable());
+        assert!(move_to(AsyncImapError::ConnectionLost).is_retryable());
+
+        let enable = |source| ImapError::Enable {
+            extension: "QRESYNC".to_string(),
+            source,
+        };
+        assert!(!enable(AsyncImapError::Bad("unknown extension".to_string())).is_retryable());
+        assert!(enable(AsyncImapError::ConnectionLost).is_retryable());
+        assert!(ImapError::Idle {
+            source: AsyncImapError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+        }
+        .is_retryable());
+
+        let connect = |kind| ImapError::Connect {
+            address: "localhost:993".to_string(),
+            source: io::Error::from(kind),
+        };
+        assert!(connect(io::ErrorKind::ConnectionRefused).is_retryable());
+        assert!(!connect(io::ErrorKind::InvalidInput).is_retryable());
+
+        assert!(!ImapError::Produce {
+            reason: "closed".to_string()
+        }
+        .is_retryable());
+    }
+}
@@ -1,5 +1,10 @@
+use crate::normalized::NormalizedEmail;
+use async_imap::imap_proto::types::{BodyParams, ContentEncoding};
+use async_imap::imap_proto::BodyStructure as AsyncImapBodyStructure;
 use async_imap::imap_proto::Envelope as AsyncImapEnvelope;
+use async_std::channel::Sender;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use thiserror::Error;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -108,23 +113,262 @@ impl From<&&AsyncImapEnvelope<'_>> for ImapEnvelope {
     }
 }
 
+// MIME part of the BODYSTRUCTURE tree - multiparts carry their sub-parts, message/rfc822 its envelope & body
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ImapBodyPart {
+    // Section number as used in BODY[<part>] - not set for the multipart at the top
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part: Option<String>,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub subtype: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    // Size in octets as transferred i.e. before decoding
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<ImapDisposition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<ImapEnvelope>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<ImapBodyPart>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ImapDisposition {
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+impl From<&AsyncImapBodyStructure<'_>> for ImapBodyPart {
+    fn from(body_structure: &AsyncImapBodyStructure<'_>) -> Self {
+        match body_structure {
+            AsyncImapBodyStructure::Multipart { .. } => body_part(body_structure, None),
+            _ => body_part(body_structure, Some("1".to_string())),
+        }
+    }
+}
+
+fn body_part(body_structure: &AsyncImapBodyStructure<'_>, part: Option<String>) -> ImapBodyPart {
+    let (common, other) = match body_structure {
+        AsyncImapBodyStructure::Basic { common, other, .. } => (common, Some(other)),
+        AsyncImapBodyStructure::Text { common, other, .. } => (common, Some(other)),
+        AsyncImapBodyStructure::Message { common, other, .. } => (common, Some(other)),
+        AsyncImapBodyStructure::Multipart { common, .. } => (common, None),
+    };
+
+    let mut rec = ImapBodyPart {
+        ty: common.ty.ty.to_lowercase(),
+        subtype: common.ty.subtype.to_lowercase(),
+        params: body_params(&common.ty.params),
+        disposition: common.disposition.as_ref().map(|d| ImapDisposition {
+            ty: d.ty.to_lowercase(),
+            params: body_params(&d.params),
+        }),
+        language: common
+            .language
+            .as_ref()
+            .map(|l| l.iter().map(|l| l.to_string()).collect()),
+        location: common.location.as_ref().map(|l| l.to_string()),
+        ..Default::default()
+    };
+
+    if let Some(other) = other {
+        rec.id = other.id.as_ref().map(|id| id.to_string());
+        rec.description = other.description.as_ref().map(|d| d.to_string());
+        rec.md5 = other.md5.as_ref().map(|md5| md5.to_string());
+        rec.size = Some(other.octets);
+        rec.encoding = Some(match &other.transfer_encoding {
+            ContentEncoding::SevenBit => "7bit".to_string(),
+            ContentEncoding::EightBit => "8bit".to_string(),
+            ContentEncoding::Binary => "binary".to_string(),
+            ContentEncoding::Base64 => "base64".to_string(),
+            ContentEncoding::QuotedPrintable => "quoted-printable".to_string(),
+            ContentEncoding::Other(other) => other.to_lowercase(),
+        });
+    }
+
+    // Sub-parts are numbered on from the parent e.g. 2.1, 2.2 - at the top level 1, 2 ..
+    let sub_part = |index: usize| match &part {
+        Some(part) => format!("{}.{}", part, index + 1),
+        None => (index + 1).to_string(),
+    };
+    match body_structure {
+        AsyncImapBodyStructure::Text { lines, .. } => rec.lines = Some(*lines),
+        AsyncImapBodyStructure::Message {
+            envelope,
+            body,
+            lines,
+            ..
+        } => {
+            rec.lines = Some(*lines);
+            rec.envelope = Some((&envelope).into());
+            // The encapsulated multipart shares the number of the message part
+            let encapsulated = match body.as_ref() {
+                AsyncImapBodyStructure::Multipart { .. } => {
+                    let mut encapsulated = body_part(body, part.clone());
+                    encapsulated.part = None;
+                    encapsulated
+                }
+                _ => body_part(body, Some(sub_part(0))),
+            };
+            rec.parts = vec![encapsulated];
+        }
+        AsyncImapBodyStructure::Multipart { bodies, .. } => {
+            rec.parts = bodies
+                .iter()
+                .enumerate()
+                .map(|(index, body)| body_part(body, Some(sub_part(index))))
+                .collect();
+        }
+        AsyncImapBodyStructure::Basic { .. } => {}
+    }
+    rec.part = part;
+    rec
+}
+
+fn body_params(params: &BodyParams<'_>) -> BTreeMap<String, String> {
+    match params {
+        Some(params) => params
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect(),
+        None => BTreeMap::new(),
+    }
+}
+
+// Outcome of verifying a single DKIM-Signature ourselves
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DkimVerification {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+    pub result: DkimResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DkimResult {
+    Pass,
+    Fail,
+    Temperror,
+    Permerror,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AttachmentMeta {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub content_type: String,
+    // Decoded size in bytes
+    pub size: usize,
+    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_id: Option<String>,
+    // Whether a child record carries the attachment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emitted: Option<bool>,
+}
+
+// Child record carrying a single decoded attachment
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AttachmentEvent {
+    pub mailbox: String,
+    pub uid: String,
+    pub attachment: AttachmentMeta,
+    pub content_base64: String,
+}
+
+// A single method result of an Authentication-Results header (RFC 8601)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AuthResult {
+    pub authserv_id: String,
+    pub method: String,
+    pub result: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // header.d for dkim, smtp.mailfrom for spf and header.from for dmarc
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    // DMARC policy of the domain e.g. reject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub(crate) struct ImapEvent<'msg> {
+    pub mailbox: String,
     pub uid: String,
+    // Set instead of the other fields when processing the message failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dkim_authenticated: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dkim_authenticated_error: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub dkim_verification: Option<Vec<DkimVerification>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_results: Option<Vec<AuthResult>>,
+    // Authentication-Results headers from authserv-ids not in trusted_authserv_ids
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_results_ignored: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub moved_to: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub internaldate: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub modseq: Option<u64>,
+    // RFC822.SIZE
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gmail_msgid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gmail_thrid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gmail_labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachments: Option<Vec<AttachmentMeta>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched_rules: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub flags: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub body: Option<Vec<u8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub body_utf8_lossy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normalized: Option<NormalizedEmail>,
     #[serde(skip_serializing_if = "Option::is_none", borrow)]
     pub header_parsed: Option<mail_parser::Message<'msg>>,
     #[serde(skip_serializing_if = "Option::is_none", borrow)]
@@ -133,24 +377,45 @@ pub(crate) struct ImapEvent<'msg> {
     pub header: Option<Vec<u8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub header_utf8_lossy: Option<String>,
-    // Placeholders
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub text: Option<bool>,
+    pub text: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text_utf8_lossy: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub envelope: Option<ImapEnvelope>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub body_structure: Option<bool>,
+    pub body_structure: Option<ImapBodyPart>,
 }
 
 impl<'msg> ImapEvent<'msg> {
-    pub fn new(uid: String) -> Self {
+    pub fn new(mailbox: &str, uid: String) -> Self {
         Self {
+            mailbox: mailbox.to_string(),
             uid,
             ..Default::default()
         }
     }
 }
 
+// Record as handed over from the IMAP task to the producer
+#[derive(Debug)]
+pub(crate) struct ImapRecord {
+    pub mailbox: String,
+    pub uid: u32,
+    pub key: Option<String>,
+    pub value: String,
+    // Error record - goes to the dead_letter_topic when set
+    pub error: bool,
+    // Where to acknowledge the record once it has been sent and flushed
+    pub ack: Sender<RecordAck>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RecordAck {
+    pub mailbox: String,
+    pub uid: u32,
+}
+
 #[derive(Error, Debug)]
 pub enum ImapEventError {
     #[error("Internal failure to convert Imap Event to JSON String: {0}")]
@@ -163,3 +428,108 @@ impl<'msg> TryFrom<ImapEvent<'msg>> for String {
         serde_json::to_string(&event).map_err(|e| ImapEventError::InternalConversion(e.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Section and MIME type of every part depth first
+    fn sections(part: &ImapBodyPart, out: &mut Vec<(Option<String>, String)>) {
+        out.push((part.part.clone(), format!("{}/{}", part.ty, part.subtype)));
+        for sub_part in part.parts.iter() {
+            sections(sub_part, out);
+        }
+    }
+
+    #[async_std::test]
+    async fn body_structure_parts() {
+        let raw = std::fs::read(
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/bodystructure/nested.eml"),
+        )
+        .unwrap();
+        let item = crate::mock_imap::fetch_message(&raw, &[], "(UID BODYSTRUCTURE)").await;
+        let top: ImapBodyPart = item.bodystructure().unwrap().into();
+
+        let mut parts = vec![];
+        sections(&top, &mut parts);
+        let part = |section: &str| Some(section.to_string());
+        assert_eq!(
+            parts,
+            vec![
+                (None, "multipart/mixed".to_string()),
+                (part("1"), "multipart/alternative".to_string()),
+                (part("1.1"), "text/plain".to_string()),
+                (part("1.2"), "text/html".to_string()),
+                (part("2"), "message/rfc822".to_string()),
+                // The multipart of the attached message shares its section
+                (None, "multipart/mixed".to_string()),
+                (part("2.1"), "text/plain".to_string()),
+                (part("2.2"), "application/pdf".to_string()),
+                (part("3"), "image/png".to_string()),
+            ]
+        );
+        assert_eq!(
+            top.params.get("boundary").map(|b| b.as_str()),
+            Some("outer")
+        );
+
+        let alternative = &top.parts[0];
+        let plain = &alternative.parts[0];
+        assert_eq!(
+            plain.params.get("charset").map(|c| c.as_str()),
+            Some("utf-8")
+        );
+        assert_eq!(plain.encoding.as_deref(), Some("7bit"));
+        assert_eq!((plain.size, plain.lines), (Some(26), Some(1)));
+        assert_eq!(alternative.parts[1].language, Some(vec!["en".to_string()]));
+
+        let attached = &top.parts[1];
+        assert_eq!(attached.description.as_deref(), Some("Original message"));
+        assert_eq!(
+            attached.disposition.as_ref().map(|d| d.ty.as_str()),
+            Some("inline")
+        );
+        let envelope = attached.envelope.as_ref().unwrap();
+        assert_eq!(envelope.subject.as_deref(), Some("Report"));
+        assert_eq!(
+            envelope.message_id.as_deref(),
+            Some("<report-1@example.net>")
+        );
+
+        let pdf = &attached.parts[0].parts[1];
+        assert_eq!(pdf.id.as_deref(), Some("<pdf-1@example.net>"));
+        assert_eq!(pdf.encoding.as_deref(), Some("base64"));
+        assert_eq!((pdf.size, pdf.lines), (Some(8), None));
+        let disposition = pdf.disposition.as_ref().unwrap();
+        assert_eq!(disposition.ty, "attachment");
+        assert_eq!(
+            disposition.params.get("filename").map(|f| f.as_str()),
+            Some("report.pdf")
+        );
+
+        let png = &top.parts[2];
+        assert_eq!(
+            png.location.as_deref(),
+            Some("https://example.com/logo.png")
+        );
+        assert_eq!(png.size, Some(12));
+    }
+
+    #[async_std::test]
+    async fn single_part_body_structure() {
+        let raw = b"From: alice@example.com\r\nSubject: Plain\r\n\r\nJust text\r\non two lines";
+        let item = crate::mock_imap::fetch_message(raw, &[], "(UID BODYSTRUCTURE)").await;
+        let part: ImapBodyPart = item.bodystructure().unwrap().into();
+
+        // A message without Content-Type is text/plain in us-ascii and its single part is 1
+        assert_eq!(part.part.as_deref(), Some("1"));
+        assert_eq!((part.ty.as_str(), part.subtype.as_str()), ("text", "plain"));
+        assert_eq!(
+            part.params.get("charset").map(|c| c.as_str()),
+            Some("us-ascii")
+        );
+        assert_eq!((part.size, part.lines), (Some(23), Some(2)));
+        assert!(part.parts.is_empty());
+    }
+}
@@ -1,7 +1,11 @@
-use crate::config::ImapConfig;
-use anyhow::Result;
+use crate::checkpoint::Checkpoints;
+use crate::config::{ImapConfig, ReconnectConfig};
+use crate::error::ImapError;
+use anyhow::{bail, Result};
+use async_imap::error::Error as AsyncImapError;
 use async_imap::extensions::idle::IdleResponse;
-use async_imap::imap_proto::types::{MailboxDatum, Response as ImapResponse};
+use async_imap::imap_proto::types::{AttributeValue, MailboxDatum, Response as ImapResponse};
+use async_imap::types::NameAttribute;
 use async_imap::Session as ImapSession;
 
 #[allow(unused_imports)]
@@ -9,8 +13,9 @@ use fluvio_connector_common::tracing::{debug, info, trace, warn};
 
 use async_std::io::{Read, Write};
 use core::fmt;
+use rand::Rng;
 use std::collections::HashMap;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_std::stream::StreamExt;
 
@@ -57,11 +62,44 @@ where
         None => info!("config.dkim_unathenticated_move was not set - Will not move any DKIM Non-Authenticated emails."),
     }
 
+    // Deleted messages are expunged by UID only - a plain EXPUNGE would remove whatever else is marked \Deleted
+    if config.deletes_messages()
+        && !fetch_session
+            .capabilities()
+            .await
+            .map_err(|source| ImapError::Capabilities { source })?
+            .has_str("UIDPLUS")
+    {
+        return Err(ImapError::Capability {
+            capability: "UIDPLUS".to_string(),
+            required_for: "the delete action".to_string(),
+        }
+        .into());
+    }
+
+    // Copy / Move destinations of the on_produced actions
+    for destination in config.destination_mailboxes() {
+        if !ensure_mailboxes_exist.contains_key(destination) {
+            info!(
+                "Will copy or move emails to mailbox {} - Checking the existence.",
+                destination
+            );
+            ensure_mailboxes_exist.insert(destination.clone(), MboxCheck::default());
+        }
+    }
+
     if !ensure_mailboxes_exist.is_empty() {
-        let mut list = fetch_session.list(Some("*"), Some("*")).await.unwrap();
+        let list_error = |source| ImapError::List {
+            pattern: "*".to_string(),
+            source,
+        };
+        let mut list = fetch_session
+            .list(Some("*"), Some("*"))
+            .await
+            .map_err(list_error)?;
 
         while let Some(item) = list.next().await {
-            let saw = item.expect("Failed to fetch Mailbox list");
+            let saw = item.map_err(list_error)?;
             let saw_name = saw.name();
             if let Some(ref mut mbox) = &mut ensure_mailboxes_exist.get_mut(saw_name) {
                 mbox.exists = true;
@@ -72,7 +110,13 @@ where
         for (create_mailbox, check_status) in ensure_mailboxes_exist.iter() {
             if !check_status.exists {
                 info!("Creating needed mailbox {}", &create_mailbox);
-                fetch_session.create(&create_mailbox).await?;
+                fetch_session
+                    .create(&create_mailbox)
+                    .await
+                    .map_err(|source| ImapError::Create {
+                        mailbox: create_mailbox.clone(),
+                        source,
+                    })?;
             } else {
                 info!("Mailbox already exists {}", &create_mailbox);
             }
@@ -82,6 +126,452 @@ where
     Ok(do_dkim_auth)
 }
 
+// Server extensions allowing incremental sync - RFC 7162
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SyncExtensions {
+    pub condstore: bool,
+    pub qresync: bool,
+    // X-GM-EXT-1 - Gmail message & thread ids and labels
+    pub gmail: bool,
+}
+
+pub(crate) async fn enable_sync_extensions<T>(
+    fetch_session: &mut ImapSession<T>,
+) -> Result<SyncExtensions>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    let capabilities = fetch_session
+        .capabilities()
+        .await
+        .map_err(|source| ImapError::Capabilities { source })?;
+
+    // QRESYNC implies CONDSTORE
+    let qresync = capabilities.has_str("QRESYNC");
+    let condstore = qresync || capabilities.has_str("CONDSTORE");
+
+    if qresync {
+        fetch_session
+            .run_command_and_check_ok("ENABLE QRESYNC")
+            .await
+            .map_err(|source| ImapError::Enable {
+                extension: "QRESYNC".to_string(),
+                source,
+            })?;
+        info!("Server supports QRESYNC - Enabled, will track HIGHESTMODSEQ and VANISHED.");
+    } else if condstore {
+        info!("Server supports CONDSTORE - Will track HIGHESTMODSEQ.");
+    } else {
+        info!("Server does not support CONDSTORE - Will search the whole mailbox upon changes.");
+    }
+
+    let gmail = capabilities.has_str("X-GM-EXT-1");
+    if gmail {
+        info!("Server supports X-GM-EXT-1 - Gmail ids & labels can be fetched.");
+    }
+
+    Ok(SyncExtensions {
+        condstore,
+        qresync,
+        gmail,
+    })
+}
+
+// Gmail attributes of a message - async-imap parses but does not expose these on Fetch
+#[derive(Debug, Default, Clone)]
+pub(crate) struct GmailAttributes {
+    pub msgid: Option<u64>,
+    pub thrid: Option<u64>,
+    pub labels: Option<Vec<String>>,
+}
+
+fn is_gmail_attribute(item: &str) -> bool {
+    item.get(..5)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("X-GM-"))
+}
+
+// Whether the configured fetch asks for any of the Gmail attributes
+pub(crate) fn wants_gmail_attributes(fetch: &str) -> bool {
+    fetch_items(fetch)
+        .iter()
+        .any(|item| is_gmail_attribute(item))
+}
+
+// The fetch without the Gmail attributes - async-imap drops them, they are fetched apart instead
+pub(crate) fn without_gmail_attributes(fetch: &str) -> String {
+    let items: Vec<String> = fetch_items(fetch)
+        .into_iter()
+        .filter(|item| !is_gmail_attribute(item))
+        .collect();
+    match items.is_empty() {
+        true => "(UID)".to_string(),
+        false => format!("({})", items.join(" ")),
+    }
+}
+
+// Fetch X-GM-MSGID, X-GM-THRID & X-GM-LABELS of the UIDs by UID
+pub(crate) async fn gmail_attributes<T>(
+    fetch_session: &mut ImapSession<T>,
+    uid_set: &str,
+) -> Result<HashMap<u32, GmailAttributes>, AsyncImapError>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    let raw = fetch_session
+        .run_command_and_read_response(format!(
+            "UID FETCH {} (UID X-GM-MSGID X-GM-THRID X-GM-LABELS)",
+            uid_set
+        ))
+        .await?;
+    Ok(parse_gmail_attributes(&raw))
+}
+
+fn parse_gmail_attributes(raw: &[u8]) -> HashMap<u32, GmailAttributes> {
+    let mut attributes = HashMap::new();
+    let mut rest = raw;
+    while !rest.is_empty() {
+        let (remaining, response) = match async_imap::imap_proto::parser::parse_response(rest) {
+            Ok(parsed) => parsed,
+            // Carry on with the next response - the message goes without its Gmail attributes
+            Err(e) => {
+                warn!("Could not parse Gmail attributes response: {:?}", e);
+                match rest.windows(4).position(|w| w == b"\r\n* ") {
+                    Some(end) => {
+                        rest = &rest[end + 2..];
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+        };
+        rest = remaining;
+
+        let attrs = match response {
+            ImapResponse::Fetch(_, attrs) => attrs,
+            _ => continue,
+        };
+        let mut uid = None;
+        let mut gmail = GmailAttributes::default();
+        for attr in attrs {
+            match attr {
+                AttributeValue::Uid(u) => uid = Some(u),
+                AttributeValue::GmailMsgId(msgid) => gmail.msgid = Some(msgid),
+                AttributeValue::GmailThrId(thrid) => gmail.thrid = Some(thrid),
+                AttributeValue::GmailLabels(labels) => {
+                    gmail.labels = Some(labels.iter().map(|l| l.to_string()).collect())
+                }
+                _ => {}
+            }
+        }
+        if let Some(uid) = uid {
+            attributes.insert(uid, gmail);
+        }
+    }
+    attributes
+}
+
+// Data items of a FETCH attribute list e.g. `(UID BODY.PEEK[HEADER.FIELDS (FROM)] RFC822.SIZE)`
+// - parentheses & spaces within a section are part of the item
+pub(crate) fn fetch_items(fetch: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut item = String::new();
+    let mut depth = 0usize;
+    for c in fetch.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        if depth == 0 && (c.is_ascii_whitespace() || c == '(' || c == ')') {
+            if !item.is_empty() {
+                items.push(std::mem::take(&mut item));
+            }
+        } else {
+            item.push(c);
+        }
+    }
+    if !item.is_empty() {
+        items.push(item);
+    }
+    items
+}
+
+// Whether the fetch asks for the data item itself - RFC822 but not RFC822.SIZE
+pub(crate) fn fetches_item(fetch: &str, item: &str) -> bool {
+    fetch_items(fetch)
+        .iter()
+        .any(|i| i.eq_ignore_ascii_case(item))
+}
+
+// Whether the fetch returns the full RFC 5322 message - partial fetches e.g. BODY[]<0.1024> do not
+pub(crate) fn fetches_whole_message(fetch: &str) -> bool {
+    fetch_items(fetch).iter().any(|i| {
+        matches!(
+            i.to_ascii_uppercase().as_str(),
+            "RFC822" | "BODY[]" | "BODY.PEEK[]"
+        )
+    })
+}
+
+// Whether FLAGS is fetched, on its own or by the ALL, FAST & FULL macros
+pub(crate) fn fetches_flags(fetch: &str) -> bool {
+    ["FLAGS", "ALL", "FAST", "FULL"]
+        .iter()
+        .any(|item| fetches_item(fetch, item))
+}
+
+// Ensure MODSEQ is among the fetched items so it can be reported per message
+pub(crate) fn with_modseq(fetch: &str) -> String {
+    let fetch = fetch.trim();
+    if fetches_item(fetch, "MODSEQ") {
+        return fetch.to_string();
+    }
+    match fetch.strip_prefix('(').and_then(|f| f.strip_suffix(')')) {
+        Some(items) => format!("({} MODSEQ)", items),
+        None => format!("({} MODSEQ)", fetch),
+    }
+}
+
+// Exponential backoff for the given reconnect attempt (1-based) with +/- jitter fraction applied
+pub(crate) fn reconnect_delay(reconnect: &ReconnectConfig, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let delay_ms = (reconnect.initial_delay_ms as f64
+        * reconnect.multiplier.max(1.0).powi(exponent))
+    .min(reconnect.max_delay_ms as f64);
+
+    let jitter = reconnect.jitter.clamp(0.0, 1.0);
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+
+    Duration::from_millis((delay_ms * factor) as u64)
+}
+
+// Expand the configured mailboxes - entries with LIST wildcards (* or %) are resolved against the server
+pub(crate) async fn resolve_mailboxes<T>(
+    config: &ImapConfig,
+    fetch_session: &mut ImapSession<T>,
+) -> Result<Vec<String>>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    // Never watch where we move mails into - we would produce them again
+    let move_targets = config.destination_mailboxes();
+
+    let mut mailboxes: Vec<String> = vec![];
+    for pattern in config.mailbox.patterns() {
+        if !pattern.contains(['*', '%']) {
+            if !mailboxes.contains(pattern) {
+                mailboxes.push(pattern.clone());
+            }
+            continue;
+        }
+
+        let list_error = |source| ImapError::List {
+            pattern: pattern.clone(),
+            source,
+        };
+        let mut list = fetch_session
+            .list(Some(""), Some(pattern.as_str()))
+            .await
+            .map_err(list_error)?;
+        let mut matched = vec![];
+        while let Some(item) = list.next().await {
+            let name = item.map_err(list_error)?;
+            if name
+                .attributes()
+                .iter()
+                .any(|a| matches!(a, NameAttribute::NoSelect))
+            {
+                continue;
+            }
+            matched.push(name.name().to_string());
+        }
+        drop(list);
+
+        debug!("Mailbox pattern {} matched {:?}", pattern, &matched);
+        for mailbox in matched {
+            if !move_targets.contains(&&mailbox) && !mailboxes.contains(&mailbox) {
+                mailboxes.push(mailbox);
+            }
+        }
+    }
+
+    if mailboxes.is_empty() {
+        bail!("No mailboxes matched {:?}", config.mailbox.patterns());
+    }
+    check_mailbox_topics(config, &mailboxes)?;
+    Ok(mailboxes)
+}
+
+// Fill {mailbox} of the template with the mailbox name made safe for a topic name
+pub(crate) fn mailbox_topic_name(template: &str, mailbox: &str) -> String {
+    let mailbox: String = mailbox
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    template.replace("{mailbox}", mailbox.trim_matches('-'))
+}
+
+// Mailboxes must not share a topic with mailbox_topic - e.g. Projects/A & Projects-A both map to mail-projects-a
+pub(crate) fn check_mailbox_topics(
+    config: &ImapConfig,
+    mailboxes: &[String],
+) -> Result<(), ImapError> {
+    let template = match &config.mailbox_topic {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+    let mut topics: HashMap<String, &String> = HashMap::new();
+    for mailbox in mailboxes {
+        let topic = mailbox_topic_name(template, mailbox);
+        match topics.get(&topic) {
+            Some(other) if *other != mailbox => {
+                return Err(ImapError::MailboxTopic {
+                    mailbox: mailbox.clone(),
+                    other: other.to_string(),
+                    topic,
+                })
+            }
+            _ => {
+                topics.insert(topic, mailbox);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Servers without IDLE e.g. legacy Exchange and appliances are polled instead
+pub(crate) async fn supports_idle<T>(idle_session: &mut ImapSession<T>) -> Result<bool>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    Ok(idle_session
+        .capabilities()
+        .await
+        .map_err(|source| ImapError::Capabilities { source })?
+        .has_str("IDLE"))
+}
+
+// Ask to be notified about new mail in all the watched mailboxes while idling - RFC 5465
+// Returns false when the server does not support or rejects NOTIFY and the mailboxes need polling instead
+pub(crate) async fn enable_notify<T>(
+    idle_session: &mut ImapSession<T>,
+    mailboxes: &[String],
+) -> Result<bool>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    if !idle_session
+        .capabilities()
+        .await
+        .map_err(|source| ImapError::Capabilities { source })?
+        .has_str("NOTIFY")
+    {
+        info!("Server does not support NOTIFY - Will poll the mailboxes instead.");
+        return Ok(false);
+    }
+
+    let quoted: Vec<String> = mailboxes.iter().map(|m| quote_mailbox(m)).collect();
+    let res = idle_session
+        .run_command_and_check_ok(format!(
+            "NOTIFY SET (selected (MessageNew MessageExpunge)) (mailboxes ({}) (MessageNew MessageExpunge))",
+            quoted.join(" ")
+        ))
+        .await;
+    match res {
+        Ok(()) => {}
+        // e.g. too many mailboxes or events the server does not support
+        Err(AsyncImapError::No(reason)) | Err(AsyncImapError::Bad(reason)) => {
+            warn!(
+                "Server rejected NOTIFY: {} - Will poll the mailboxes instead.",
+                reason
+            );
+            return Ok(false);
+        }
+        Err(source) => {
+            return Err(ImapError::Enable {
+                extension: "NOTIFY".to_string(),
+                source,
+            }
+            .into())
+        }
+    }
+
+    info!(
+        "Server supports NOTIFY - Enabled for {} mailboxes.",
+        mailboxes.len()
+    );
+    Ok(true)
+}
+
+fn quote_mailbox(mailbox: &str) -> String {
+    format!("\"{}\"", mailbox.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// STATUS every mailbox and return those which may have mail past the checkpoint
+pub(crate) async fn changed_mailboxes<T>(
+    fetch_session: &mut ImapSession<T>,
+    mailboxes: &[String],
+    checkpoints: &Checkpoints,
+) -> Result<Vec<String>>
+where
+    T: Read + Write + Unpin + Send + fmt::Debug,
+{
+    let mut changed = vec![];
+    for mailbox in mailboxes {
+        let status = fetch_session
+            .status(mailbox, "(UIDNEXT UIDVALIDITY)")
+            .await
+            .map_err(|source| ImapError::Status {
+                mailbox: mailbox.clone(),
+                source,
+            })?;
+
+        let unchanged = match (checkpoints.get(mailbox), status.uid_next) {
+            (Some(checkpoint), Some(uid_next)) => {
+                status.uid_validity == Some(checkpoint.uid_validity)
+                    && uid_next <= checkpoint.last_uid.saturating_add(1)
+            }
+            _ => false,
+        };
+
+        if unchanged {
+            trace!(
+                "Mailbox {} unchanged - UIDNEXT {:?}",
+                mailbox,
+                status.uid_next
+            );
+        } else {
+            changed.push(mailbox.clone());
+        }
+    }
+    Ok(changed)
+}
+
+// UID set for commands operating on several messages at once
+pub(crate) fn uid_set(uids: &[u32]) -> String {
+    let mut sorted = uids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    // Consecutive UIDs collapse into ranges e.g. 1:100,105,200:250
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for uid in sorted {
+        match ranges.last_mut() {
+            Some((_, end)) if end.checked_add(1) == Some(uid) => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}:{}", start, end),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
 // idle connection may spit out irrelevant notifications we will ignore
 // re-calculate the new idle time based on duration if needed
 pub(crate) fn calculate_idle_left(before: SystemTime, idle_secs_setting: u64) -> u64 {
@@ -105,15 +595,20 @@ pub(crate) fn calculate_idle_left(before: SystemTime, idle_secs_setting: u64) ->
 // we are interested on
 pub(crate) fn is_idle_response_interesting(
     idle_res: &IdleResponse,
-    interesting_mailbox: &str,
+    interesting_mailboxes: &[String],
 ) -> bool {
     match idle_res {
         IdleResponse::NewData(data) => {
             let parsed = data.parsed();
             match parsed {
                 ImapResponse::MailboxData(mailbox_data) => match mailbox_data {
+                    // New mail in the mailbox we are idling on
+                    MailboxDatum::Exists(exists) => {
+                        info!("Mailbox exists update on idle mailbox = {:?}", exists);
+                        true
+                    }
                     MailboxDatum::Status { mailbox, status } => {
-                        if *mailbox == interesting_mailbox {
+                        if interesting_mailboxes.iter().any(|m| m == mailbox) {
                             info!(
                                 "Mailbox update on interested mailbox {:?} = {:?}",
                                 mailbox, status
@@ -142,3 +637,173 @@ pub(crate) fn is_idle_response_interesting(
         IdleResponse::ManualInterrupt => true,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_backoff() {
+        let reconnect = |multiplier, jitter| ReconnectConfig {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            multiplier,
+            jitter,
+            max_attempts: 10,
+        };
+        let millis = |reconnect: &ReconnectConfig, attempt| {
+            reconnect_delay(reconnect, attempt).as_millis() as u64
+        };
+
+        // Doubling from the initial delay up to the cap
+        let exact = reconnect(2.0, 0.0);
+        let delays: Vec<u64> = (1..=8).map(|attempt| millis(&exact, attempt)).collect();
+        assert_eq!(
+            delays,
+            [1000, 2000, 4000, 8000, 16_000, 32_000, 60_000, 60_000]
+        );
+        assert_eq!(millis(&exact, 0), 1000);
+        assert_eq!(millis(&exact, u32::MAX), 60_000);
+
+        // A multiplier below 1 would shrink the delay - it stays at the initial delay instead
+        assert_eq!(millis(&reconnect(0.5, 0.0), 5), 1000);
+        // Negative jitter counts as none
+        assert_eq!(millis(&reconnect(2.0, -0.5), 2), 2000);
+
+        // +/- 20% around the delay, the cap included
+        let jittered = reconnect(2.0, 0.2);
+        for _ in 0..200 {
+            assert!((800..=1200).contains(&millis(&jittered, 1)));
+            assert!((48_000..=72_000).contains(&millis(&jittered, 20)));
+        }
+        // Jitter is at most the delay itself
+        let wild = reconnect(2.0, 5.0);
+        for _ in 0..200 {
+            assert!(millis(&wild, 2) <= 4000);
+        }
+    }
+
+    #[test]
+    fn uid_set_ranges() {
+        let uids: Vec<u32> = (1..=100).chain([105]).chain(200..=250).collect();
+        assert_eq!(uid_set(&uids), "1:100,105,200:250");
+        assert_eq!(uid_set(&[7, 3, 4, 5, 3, 9]), "3:5,7,9");
+        assert_eq!(uid_set(&[42]), "42");
+        assert_eq!(uid_set(&[u32::MAX - 1, u32::MAX]), "4294967294:4294967295");
+        assert_eq!(uid_set(&[]), "");
+    }
+
+    #[test]
+    fn mailbox_topics() {
+        assert_eq!(
+            mailbox_topic_name("mail-{mailbox}", "Projects/Q3 Plans"),
+            "mail-projects-q3-plans"
+        );
+        assert_eq!(
+            mailbox_topic_name("mail-{mailbox}", "[Gmail]/Sent"),
+            "mail-gmail--sent"
+        );
+
+        let config = crate::mock_imap::config(
+            993,
+            false,
+            serde_json::json!({"mailbox_topic": "mail-{mailbox}"}),
+        );
+        let mailboxes = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(check_mailbox_topics(&config, &mailboxes(&["INBOX", "Projects/A"])).is_ok());
+        match check_mailbox_topics(&config, &mailboxes(&["INBOX", "Projects/A", "Projects-A"])) {
+            Err(ImapError::MailboxTopic {
+                mailbox,
+                other,
+                topic,
+            }) => {
+                assert_eq!(mailbox, "Projects-A");
+                assert_eq!(other, "Projects/A");
+                assert_eq!(topic, "mail-projects-a");
+            }
+            other => panic!("{:?}", other),
+        }
+        // Only the connector topic without mailbox_topic
+        let config = crate::mock_imap::config(993, false, serde_json::json!({}));
+        assert!(check_mailbox_topics(&config, &mailboxes(&["Projects/A", "Projects-A"])).is_ok());
+    }
+
+    #[test]
+    fn fetch_item_boundaries() {
+        let fetch = "(UID RFC822.SIZE BODY.PEEK[HEADER.FIELDS (FROM TO)] FLAGS)";
+        assert_eq!(
+            fetch_items(fetch),
+            [
+                "UID",
+                "RFC822.SIZE",
+                "BODY.PEEK[HEADER.FIELDS (FROM TO)]",
+                "FLAGS"
+            ]
+        );
+        assert!(fetches_item(fetch, "uid"));
+        assert!(!fetches_item(fetch, "RFC822"));
+        assert!(!fetches_item(fetch, "FROM"));
+        assert!(!fetches_whole_message(fetch));
+
+        assert!(fetches_whole_message("RFC822"));
+        assert!(fetches_whole_message("(UID rfc822)"));
+        assert!(fetches_whole_message("(UID BODY[] FLAGS)"));
+        assert!(fetches_whole_message("(UID BODY.PEEK[])"));
+        assert!(!fetches_whole_message("(UID RFC822.HEADER)"));
+        assert!(!fetches_whole_message("(UID BODY[]<0.1024>)"));
+        assert!(!fetches_whole_message("(UID BODY[TEXT])"));
+
+        assert!(fetches_flags("(UID flags)"));
+        assert!(fetches_flags("FAST"));
+        assert!(!fetches_flags("(UID BODY.PEEK[HEADER.FIELDS (X-FLAGS)])"));
+        assert!(!fetches_flags("(UID X-GM-LABELS)"));
+    }
+
+    #[test]
+    fn gmail_attributes_apart() {
+        let fetch = "(UID FLAGS x-gm-labels BODY.PEEK[HEADER.FIELDS (X-GM-Test)] X-GM-MSGID)";
+        assert!(wants_gmail_attributes(fetch));
+        assert!(!wants_gmail_attributes(
+            "(UID BODY.PEEK[HEADER.FIELDS (X-GM-Test)])"
+        ));
+        assert_eq!(
+            without_gmail_attributes(fetch),
+            "(UID FLAGS BODY.PEEK[HEADER.FIELDS (X-GM-Test)])"
+        );
+        assert_eq!(without_gmail_attributes("X-GM-THRID"), "(UID)");
+    }
+
+    #[test]
+    fn gmail_attributes_past_parse_error() {
+        let raw =
+            b"* 1 FETCH (UID 11 X-GM-MSGID 101 X-GM-THRID 201 X-GM-LABELS (\\Inbox Work))\r\n\
+            * 2 FETCH (UID 12 X-GM-MSGID 102 X-GM-LABELS {4}\r\nWork)\r\n\
+            * 3 FETCH (UID 13 X-GM-MSGID 103 X-GM-THRID 203 X-GM-LABELS (\"Muy Importante\"))\r\n\
+            A1 OK Success\r\n";
+        let attributes = parse_gmail_attributes(raw);
+
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes[&11].msgid, Some(101));
+        assert_eq!(attributes[&11].thrid, Some(201));
+        assert_eq!(
+            attributes[&11].labels,
+            Some(vec!["\\Inbox".to_string(), "Work".to_string()])
+        );
+        assert!(!attributes.contains_key(&12));
+        assert_eq!(
+            attributes[&13].labels,
+            Some(vec!["Muy Importante".to_string()])
+        );
+    }
+
+    #[test]
+    fn modseq_added_once() {
+        assert_eq!(with_modseq("(UID FLAGS)"), "(UID FLAGS MODSEQ)");
+        assert_eq!(with_modseq("RFC822"), "(RFC822 MODSEQ)");
+        assert_eq!(with_modseq("(UID modseq)"), "(UID modseq)");
+        assert_eq!(
+            with_modseq("(UID BODY.PEEK[HEADER.FIELDS (X-MODSEQ)])"),
+            "(UID BODY.PEEK[HEADER.FIELDS (X-MODSEQ)] MODSEQ)"
+        );
+    }
+}
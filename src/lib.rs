@@ -0,0 +1,108 @@
+mod ack;
+mod actions;
+mod attachment;
+mod auth;
+mod auth_results;
+mod checkpoint;
+pub mod config;
+mod connection;
+mod dkim;
+mod error;
+mod event;
+mod imap_util;
+#[cfg(test)]
+mod mock_imap;
+mod normalized;
+mod producer;
+mod record;
+mod rules;
+mod source;
+mod util;
+
+use config::ImapConfig;
+use event::{ImapRecord, RecordAck};
+
+use async_imap::types::Fetch;
+use fluvio::{RecordKey, TopicProducer};
+use fluvio_connector_common::{
+    tracing::{debug, error, info, trace},
+    Result, Source,
+};
+use futures::StreamExt;
+use producer::TopicProducers;
+use source::ImapSource;
+
+// Records produced before flushing & acknowledging them back to the IMAP task
+const PRODUCE_BATCH_SIZE: usize = 1000;
+
+// Produce the records of the IMAP source - the connector entry point in main.rs hands over here
+pub async fn run(config: ImapConfig, producer: TopicProducer) -> Result<()> {
+    debug!(?config);
+    let mut topic_producers = TopicProducers::new(&config)?;
+    if let Some(topic) = &config.dead_letter_topic {
+        info!("Producing error records to dead letter topic {}", topic);
+    }
+
+    let source = ImapSource::new(config)?;
+    let mut stream = source.connect(None).await?.ready_chunks(PRODUCE_BATCH_SIZE);
+    while let Some(items) = stream.next().await {
+        let mut acks = Vec::with_capacity(items.len());
+
+        for item in items {
+            trace!(?item);
+            let ImapRecord {
+                mailbox,
+                uid,
+                key,
+                value,
+                error,
+                ack,
+            } = item;
+
+            let key = match key {
+                Some(key) => RecordKey::from(key),
+                None => RecordKey::NULL,
+            };
+            match topic_producers.topic(&mailbox, error) {
+                Some(topic) => {
+                    topic_producers
+                        .producer(&topic)
+                        .await?
+                        .send(key, value)
+                        .await?;
+                }
+                None => {
+                    producer.send(key, value).await?;
+                }
+            }
+            acks.push((ack, RecordAck { mailbox, uid }));
+        }
+
+        // Only once the records are in Fluvio the IMAP task may move / flag the messages
+        producer.flush().await?;
+        topic_producers.flush().await?;
+        for (ack, record_ack) in acks {
+            if let Err(e) = ack.send(record_ack).await {
+                debug!("IMAP task gone before acknowledgement: {}", e);
+            }
+        }
+    }
+    error!("IMAP source stream ended - Stopping.");
+    Ok(())
+}
+
+// Entry point of the fuzz targets in fuzz/ - the FETCH item through fill_record & the JSON conversion
+#[doc(hidden)]
+pub fn fuzz_fill_record(config: &ImapConfig, item: &Fetch) {
+    let rec = record::fill_record(
+        config,
+        "INBOX",
+        "1".to_string(),
+        item,
+        config.mode_dkim_auth,
+        None,
+    );
+    if let Ok(rec) = rec {
+        let _json: std::result::Result<String, _> = rec.try_into();
+    }
+}
@@ -0,0 +1,1162 @@
+// Scripted in-process IMAP server driving the IMAP loop end to end in the tests.
+// Supports just enough of RFC 3501 & friends for the connector: LOGIN, AUTHENTICATE XOAUTH2 (the password being the
+// access token), CAPABILITY, LIST, CREATE, SELECT, STATUS,
+// NOOP, IDLE, UID SEARCH / FETCH / STORE / COPY / MOVE / EXPUNGE and LOGOUT - over plain TCP or self-signed TLS,
+// implicit or after STARTTLS.
+// FETCH renders ENVELOPE and BODYSTRUCTURE from the message itself the way servers do.
+// Every mailbox keeps modseqs (RFC 7162) - HIGHESTMODSEQ, MODSEQ, CHANGEDSINCE & ENABLE once CONDSTORE / QRESYNC
+// is among the capabilities. NOTIFY SET (RFC 5465) makes the idling connections hear about every mailbox.
+use crate::auth::TokenProvider;
+use crate::config::ImapConfig;
+use async_imap::types::Fetch;
+use async_native_tls::TlsAcceptor;
+use async_std::channel::{self, Sender};
+use async_std::io::{BufReadExt, BufReader, Read, Write, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task::spawn;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use futures::io::AsyncReadExt;
+use futures::StreamExt;
+use mail_parser::{Address, HeaderValue, MimeHeaders};
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CAPABILITIES: &str = "IMAP4rev1 IDLE UIDPLUS MOVE";
+const INTERNALDATE: &str = "05-Jul-2024 02:26:27 +0000";
+
+// Configuration of the connector against the mock server on the port - extra overrides the defaults
+pub(crate) fn config(port: u16, tls: bool, extra: serde_json::Value) -> ImapConfig {
+    let mut config = serde_json::json!({
+        "host": "localhost",
+        "port": port.to_string(),
+        "user": "user",
+        "password": "secret",
+        "mailbox": "INBOX",
+        "search": "ALL",
+        "fetch": "(UID FLAGS RFC822.SIZE RFC822)",
+        "mode_bytes": false,
+        "mode_utf8_lossy": true,
+        "mode_parser": false,
+        "mode_dkim_auth": false,
+        "dangerous_cert": tls,
+        "tls_mode": if tls { "implicit" } else { "none" },
+        "allow_insecure_plaintext": !tls,
+    });
+    if let (Some(config), serde_json::Value::Object(extra)) = (config.as_object_mut(), extra) {
+        config.extend(extra);
+    }
+    serde_json::from_value(config).unwrap()
+}
+
+// The message with its flags as async-imap hands it out for the fetch query - for the tests of what is built from a Fetch
+pub(crate) async fn fetch_message(raw: &[u8], flags: &[&str], fetch: &str) -> Fetch {
+    let server = MockImapServer::start("user", "secret", None).await;
+    server
+        .state
+        .lock()
+        .unwrap()
+        .mailboxes
+        .get_mut("INBOX")
+        .unwrap()
+        .append(raw.to_vec(), flags.iter().map(|f| f.to_string()).collect());
+
+    let config = config(server.port, false, serde_json::json!({}));
+    let client = crate::connection::connect(&config).await.unwrap();
+    let mut session = crate::auth::login(client, &config, &mut TokenProvider::new(None))
+        .await
+        .unwrap();
+    session.select("INBOX").await.unwrap();
+    let mut fetches = session.uid_fetch("1", fetch).await.unwrap();
+    let item = fetches.next().await.unwrap().unwrap();
+    drop(fetches);
+    item
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MockMessage {
+    pub uid: u32,
+    pub flags: Vec<String>,
+    pub raw: Vec<u8>,
+    pub modseq: u64,
+}
+
+// How the next matching command fails
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Failure {
+    No,
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct MockMailbox {
+    uid_validity: u32,
+    uid_next: u32,
+    highest_modseq: u64,
+    messages: Vec<MockMessage>,
+}
+
+impl MockMailbox {
+    fn new(uid_validity: u32) -> Self {
+        Self {
+            uid_validity,
+            uid_next: 1,
+            highest_modseq: 1,
+            messages: vec![],
+        }
+    }
+
+    fn append(&mut self, raw: Vec<u8>, flags: Vec<String>) -> u32 {
+        let uid = self.uid_next;
+        self.uid_next += 1;
+        let modseq = self.next_modseq();
+        self.messages.push(MockMessage {
+            uid,
+            flags,
+            raw,
+            modseq,
+        });
+        uid
+    }
+
+    // Every change of the mailbox gets a new modseq
+    fn next_modseq(&mut self) -> u64 {
+        self.highest_modseq += 1;
+        self.highest_modseq
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    capabilities: String,
+    user: String,
+    password: String,
+    mailboxes: BTreeMap<String, MockMailbox>,
+    // One-shot failures by command prefix e.g. "UID FETCH"
+    failures: Vec<(String, Failure)>,
+    // Every command received, without the tag
+    commands: Vec<String>,
+    connections: Vec<Sender<Event>>,
+}
+
+#[derive(Debug)]
+enum Event {
+    Line(String),
+    // New mail in the mailbox
+    Exists(String),
+    Closed,
+}
+
+pub(crate) struct MockImapServer {
+    pub port: u16,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockImapServer {
+    // Plain TCP when no TLS acceptor is given
+    pub(crate) async fn start(user: &str, password: &str, tls: Option<TlsAcceptor>) -> Self {
+        Self::start_with(user, password, tls, false).await
+    }
+
+    // Plaintext until the client upgrades via STARTTLS - offered when among the capabilities
+    pub(crate) async fn start_starttls(user: &str, password: &str, tls: TlsAcceptor) -> Self {
+        Self::start_with(user, password, Some(tls), true).await
+    }
+
+    async fn start_with(
+        user: &str,
+        password: &str,
+        tls: Option<TlsAcceptor>,
+        starttls: bool,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut mailboxes = BTreeMap::new();
+        mailboxes.insert("INBOX".to_string(), MockMailbox::new(1));
+        let state = Arc::new(Mutex::new(MockState {
+            capabilities: DEFAULT_CAPABILITIES.to_string(),
+            user: user.to_string(),
+            password: password.to_string(),
+            mailboxes,
+            failures: vec![],
+            commands: vec![],
+            connections: vec![],
+        }));
+
+        let accept_state = state.clone();
+        let tls = tls.map(Arc::new);
+        spawn(async move {
+            while let Ok((tcp_stream, _)) = listener.accept().await {
+                let state = accept_state.clone();
+                let tls = tls.clone();
+                spawn(async move {
+                    // Upgraded connections were greeted in plaintext already
+                    if starttls && !before_starttls(&tcp_stream, &state).await {
+                        return;
+                    }
+                    match tls {
+                        Some(acceptor) => match acceptor.accept(tcp_stream).await {
+                            Ok(tls_stream) => serve(tls_stream, state, !starttls).await,
+                            Err(e) => eprintln!("Mock IMAP TLS accept failed: {:?}", e),
+                        },
+                        None => serve(tcp_stream, state, true).await,
+                    }
+                });
+            }
+        });
+
+        Self { port, state }
+    }
+
+    // Self-signed certificate for localhost
+    pub(crate) fn self_signed_acceptor() -> TlsAcceptor {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(
+            cert.pem().as_bytes(),
+            key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        native_tls::TlsAcceptor::new(identity).unwrap().into()
+    }
+
+    // Deliver a message - connections idling on the mailbox are woken up
+    pub(crate) async fn append(&self, mailbox: &str, raw: &[u8]) -> u32 {
+        let (uid, connections) = {
+            let mut state = self.state.lock().unwrap();
+            let uid = state
+                .mailboxes
+                .entry(mailbox.to_string())
+                .or_insert_with(|| MockMailbox::new(1))
+                .append(raw.to_vec(), vec![]);
+            (uid, state.connections.clone())
+        };
+        for connection in connections {
+            let _ = connection.send(Event::Exists(mailbox.to_string())).await;
+        }
+        uid
+    }
+
+    // e.g. without IDLE like legacy servers
+    pub(crate) fn set_capabilities(&self, capabilities: &str) {
+        self.state.lock().unwrap().capabilities = capabilities.to_string();
+    }
+
+    // The next command starting with the prefix fails
+    pub(crate) fn fail_next(&self, command: &str, failure: Failure) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .push((command.to_ascii_uppercase(), failure));
+    }
+
+    pub(crate) fn messages(&self, mailbox: &str) -> Vec<MockMessage> {
+        self.state
+            .lock()
+            .unwrap()
+            .mailboxes
+            .get(mailbox)
+            .map(|m| m.messages.clone())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().commands.clone()
+    }
+}
+
+// Plaintext CAPABILITY & STARTTLS - true once the client asked for the upgrade
+async fn before_starttls(tcp_stream: &TcpStream, state: &Arc<Mutex<MockState>>) -> bool {
+    let mut reader = BufReader::new(tcp_stream);
+    let mut writer = tcp_stream;
+    let capabilities = state.lock().unwrap().capabilities.clone();
+    let greeting = format!("* OK [CAPABILITY {}] Mock IMAP ready\r\n", capabilities);
+    if writer.write_all(greeting.as_bytes()).await.is_err() {
+        return false;
+    }
+
+    let mut line = String::new();
+    while let Ok(1..) = reader.read_line(&mut line).await {
+        let (tag, command) = line
+            .trim_end()
+            .split_once(' ')
+            .unwrap_or((line.trim_end(), ""));
+        state.lock().unwrap().commands.push(command.to_string());
+        let (response, upgrade) = match command.to_ascii_uppercase().as_str() {
+            "CAPABILITY" => (
+                format!(
+                    "* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n",
+                    capabilities, tag
+                ),
+                false,
+            ),
+            "STARTTLS" if capabilities.split(' ').any(|c| c == "STARTTLS") => {
+                (format!("{} OK Begin TLS negotiation now\r\n", tag), true)
+            }
+            "LOGOUT" => return false,
+            _ => (
+                format!("{} BAD Only CAPABILITY & STARTTLS before TLS\r\n", tag),
+                false,
+            ),
+        };
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            return false;
+        }
+        if upgrade {
+            return true;
+        }
+        line.clear();
+    }
+    false
+}
+
+async fn serve<S>(stream: S, state: Arc<Mutex<MockState>>, greeting: bool)
+where
+    S: Read + Write + Unpin + Send + 'static,
+{
+    let (reader, mut writer) = stream.split();
+    let (tx, rx) = channel::unbounded();
+    state.lock().unwrap().connections.push(tx.clone());
+
+    spawn(async move {
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(Ok(line)) = lines.next().await {
+            if tx.send(Event::Line(line)).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Event::Closed).await;
+    });
+
+    if greeting {
+        let greeting = format!(
+            "* OK [CAPABILITY {}] Mock IMAP ready\r\n",
+            state.lock().unwrap().capabilities
+        );
+        if writer.write_all(greeting.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+
+    let mut connection = Connection {
+        state,
+        selected: None,
+        idle_tag: None,
+        condstore: false,
+        notify: false,
+        sasl: None,
+    };
+    while let Ok(event) = rx.recv().await {
+        let output = match event {
+            Event::Line(line) => connection.handle(&line),
+            Event::Exists(mailbox) => Some(connection.exists(&mailbox)),
+            Event::Closed => None,
+        };
+        match output {
+            Some(output) => {
+                if writer.write_all(&output).await.is_err() {
+                    return;
+                }
+            }
+            None => break,
+        }
+    }
+    // The reader half keeps the stream open - shut it down for the client to see the disconnect
+    let _ = writer.close().await;
+}
+
+struct Connection {
+    state: Arc<Mutex<MockState>>,
+    selected: Option<String>,
+    idle_tag: Option<String>,
+    // Enabled by SELECT (CONDSTORE) or ENABLE
+    condstore: bool,
+    // NOTIFY SET for all the mailboxes
+    notify: bool,
+    // Tag of the AUTHENTICATE in progress - whether the initial response was rejected already
+    sasl: Option<(String, bool)>,
+}
+
+impl Connection {
+    // Only told while idling on the mailbox or with NOTIFY - otherwise picked up by the next SELECT / SEARCH
+    fn exists(&self, mailbox: &str) -> Vec<u8> {
+        if self.idle_tag.is_none() {
+            return vec![];
+        }
+        let state = self.state.lock().unwrap();
+        let mailbox_state = match state.mailboxes.get(mailbox) {
+            Some(mailbox_state) => mailbox_state,
+            None => return vec![],
+        };
+        match self.selected.as_deref() == Some(mailbox) {
+            true => format!("* {} EXISTS\r\n", mailbox_state.messages.len()).into_bytes(),
+            false if self.notify => format!(
+                "* STATUS \"{}\" (MESSAGES {} UIDNEXT {})\r\n",
+                mailbox,
+                mailbox_state.messages.len(),
+                mailbox_state.uid_next
+            )
+            .into_bytes(),
+            false => vec![],
+        }
+    }
+
+    // XOAUTH2 the way Gmail does it - a rejected token gets an error challenge the client answers with an empty line
+    fn sasl_response(&mut self, tag: String, rejected: bool, line: &str) -> Vec<u8> {
+        if rejected {
+            return format!("{} NO [AUTHENTICATIONFAILED] Invalid credentials\r\n", tag)
+                .into_bytes();
+        }
+        let response = BASE64.decode(line.trim()).unwrap_or_default();
+        let state = self.state.lock().unwrap();
+        let expected = format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            state.user, state.password
+        );
+        match response == expected.as_bytes() {
+            true => format!("{} OK AUTHENTICATE completed\r\n", tag).into_bytes(),
+            false => {
+                self.sasl = Some((tag, true));
+                format!("+ {}\r\n", BASE64.encode(r#"{"status":"401"}"#)).into_bytes()
+            }
+        }
+    }
+
+    // The response to the line - None closes the connection
+    fn handle(&mut self, line: &str) -> Option<Vec<u8>> {
+        if let Some(tag) = self.idle_tag.take() {
+            return match line.trim().eq_ignore_ascii_case("DONE") {
+                true => Some(format!("{} OK IDLE terminated\r\n", tag).into_bytes()),
+                false => Some(format!("{} BAD Expected DONE\r\n", tag).into_bytes()),
+            };
+        }
+
+        if let Some((tag, rejected)) = self.sasl.take() {
+            return Some(self.sasl_response(tag, rejected, line));
+        }
+
+        let (tag, command) = line.split_once(' ')?;
+        let mut state = self.state.lock().unwrap();
+        state.commands.push(command.to_string());
+
+        let upper = command.to_ascii_uppercase();
+        if let Some(index) = state
+            .failures
+            .iter()
+            .position(|(prefix, _)| upper.starts_with(prefix.as_str()))
+        {
+            let (_, failure) = state.failures.remove(index);
+            return match failure {
+                Failure::No => Some(format!("{} NO Injected failure\r\n", tag).into_bytes()),
+                Failure::Disconnect => None,
+            };
+        }
+
+        let args = tokenize(command);
+        let name = args.first()?.to_ascii_uppercase();
+        let mut out: Vec<u8> = vec![];
+        let ok = |out: &mut Vec<u8>, text: &str| {
+            out.extend(format!("{} OK {}\r\n", tag, text).as_bytes());
+        };
+        let no = |out: &mut Vec<u8>, text: &str| {
+            out.extend(format!("{} NO {}\r\n", tag, text).as_bytes());
+        };
+
+        match name.as_str() {
+            "CAPABILITY" => {
+                out.extend(format!("* CAPABILITY {}\r\n", state.capabilities).as_bytes());
+                ok(&mut out, "CAPABILITY completed");
+            }
+            "LOGIN" => match (args.get(1), args.get(2)) {
+                (Some(user), Some(password))
+                    if *user == state.user && *password == state.password =>
+                {
+                    ok(&mut out, "LOGIN completed")
+                }
+                _ => no(&mut out, "[AUTHENTICATIONFAILED] Invalid credentials"),
+            },
+            "AUTHENTICATE"
+                if args
+                    .get(1)
+                    .is_some_and(|m| m.eq_ignore_ascii_case("XOAUTH2")) =>
+            {
+                self.sasl = Some((tag.to_string(), false));
+                return Some(b"+ \r\n".to_vec());
+            }
+            "NOOP" => ok(&mut out, "NOOP completed"),
+            "ENABLE" => {
+                let enabled: Vec<&str> = args[1..]
+                    .iter()
+                    .map(|e| e.as_str())
+                    .filter(|e| has_capability(&state, e))
+                    .collect();
+                self.condstore |= !enabled.is_empty();
+                out.extend(format!("* ENABLED {}\r\n", enabled.join(" ")).as_bytes());
+                ok(&mut out, "ENABLE completed");
+            }
+            "LOGOUT" => {
+                out.extend(b"* BYE Logging out\r\n");
+                ok(&mut out, "LOGOUT completed");
+            }
+            "LIST" => {
+                let pattern = args.get(2).map(|p| p.as_str()).unwrap_or("*");
+                for mailbox in state.mailboxes.keys() {
+                    if wildcard_matches(pattern, mailbox) {
+                        out.extend(
+                            format!("* LIST (\\HasNoChildren) \"/\" \"{}\"\r\n", mailbox)
+                                .as_bytes(),
+                        );
+                    }
+                }
+                ok(&mut out, "LIST completed");
+            }
+            "CREATE" => match args.get(1) {
+                Some(mailbox) => {
+                    state
+                        .mailboxes
+                        .entry(mailbox.clone())
+                        .or_insert_with(|| MockMailbox::new(1));
+                    ok(&mut out, "CREATE completed");
+                }
+                None => no(&mut out, "Mailbox missing"),
+            },
+            "SELECT" | "EXAMINE" => match args.get(1).and_then(|m| state.mailboxes.get(m)) {
+                Some(mailbox) => {
+                    let condstore = args.get(2).map(|p| p.to_ascii_uppercase());
+                    self.condstore |= condstore.as_deref() == Some("(CONDSTORE)")
+                        && has_capability(&state, "CONDSTORE");
+                    out.extend(b"* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)\r\n");
+                    out.extend(format!("* {} EXISTS\r\n", mailbox.messages.len()).as_bytes());
+                    out.extend(b"* 0 RECENT\r\n");
+                    out.extend(
+                        format!("* OK [UIDVALIDITY {}] UIDs valid\r\n", mailbox.uid_validity)
+                            .as_bytes(),
+                    );
+                    out.extend(
+                        format!("* OK [UIDNEXT {}] Predicted next UID\r\n", mailbox.uid_next)
+                            .as_bytes(),
+                    );
+                    if self.condstore {
+                        out.extend(
+                            format!(
+                                "* OK [HIGHESTMODSEQ {}] Highest\r\n",
+                                mailbox.highest_modseq
+                            )
+                            .as_bytes(),
+                        );
+                    }
+                    self.selected = args.get(1).cloned();
+                    ok(&mut out, "[READ-WRITE] SELECT completed");
+                }
+                None => {
+                    self.selected = None;
+                    no(&mut out, "Mailbox does not exist");
+                }
+            },
+            "STATUS" => match args.get(1).and_then(|m| state.mailboxes.get(m)) {
+                Some(mailbox) => {
+                    out.extend(
+                        format!(
+                            "* STATUS \"{}\" (MESSAGES {} UIDNEXT {} UIDVALIDITY {})\r\n",
+                            args[1],
+                            mailbox.messages.len(),
+                            mailbox.uid_next,
+                            mailbox.uid_validity
+                        )
+                        .as_bytes(),
+                    );
+                    ok(&mut out, "STATUS completed");
+                }
+                None => no(&mut out, "Mailbox does not exist"),
+            },
+            "NOTIFY" if has_capability(&state, "NOTIFY") => {
+                self.notify = args.get(1).is_some_and(|a| a.eq_ignore_ascii_case("SET"));
+                ok(&mut out, "NOTIFY completed");
+            }
+            "IDLE" if !has_capability(&state, "IDLE") => {
+                out.extend(format!("{} BAD Unknown command\r\n", tag).as_bytes());
+            }
+            "IDLE" => {
+                drop(state);
+                self.idle_tag = Some(tag.to_string());
+                return Some(b"+ idling\r\n".to_vec());
+            }
+            "UID" => {
+                let selected = self.selected.clone();
+                match selected
+                    .as_deref()
+                    .filter(|m| state.mailboxes.contains_key(*m))
+                {
+                    Some(selected) => {
+                        self.condstore |=
+                            upper.contains("MODSEQ") || upper.contains("CHANGEDSINCE");
+                        uid_command(&mut state, selected, tag, &args[1..], &mut out)
+                    }
+                    None => out.extend(format!("{} BAD No mailbox selected\r\n", tag).as_bytes()),
+                }
+            }
+            _ => out.extend(format!("{} BAD Unknown command\r\n", tag).as_bytes()),
+        }
+        Some(out)
+    }
+}
+
+fn uid_command(
+    state: &mut MockState,
+    selected: &str,
+    tag: &str,
+    args: &[String],
+    out: &mut Vec<u8>,
+) {
+    let command = args
+        .first()
+        .map(|c| c.to_ascii_uppercase())
+        .unwrap_or_default();
+    let uid_next = state.mailboxes[selected].uid_next;
+    let set = args
+        .get(1)
+        .map(|s| parse_set(s, uid_next))
+        .unwrap_or_default();
+
+    match command.as_str() {
+        "SEARCH" => {
+            // UID <set> plus ALL / SEEN / UNSEEN / MODSEQ - anything else matches everything
+            let mut uids: Option<Vec<(u32, u32)>> = None;
+            let mut seen: Option<bool> = None;
+            let mut modseq: Option<u64> = None;
+            let mut criteria = args[1..].iter();
+            while let Some(criterion) = criteria.next() {
+                match criterion.to_ascii_uppercase().as_str() {
+                    "UID" => uids = criteria.next().map(|s| parse_set(s, uid_next)),
+                    "SEEN" => seen = Some(true),
+                    "UNSEEN" => seen = Some(false),
+                    "MODSEQ" => modseq = criteria.next().and_then(|m| m.parse().ok()),
+                    _ => {}
+                }
+            }
+            let found: Vec<&MockMessage> = state.mailboxes[selected]
+                .messages
+                .iter()
+                .filter(|m| uids.as_ref().map_or(true, |u| in_set(u, m.uid)))
+                .filter(|m| seen.map_or(true, |s| m.flags.iter().any(|f| f == "\\Seen") == s))
+                .filter(|m| modseq.map_or(true, |modseq| m.modseq >= modseq))
+                .collect();
+            let mut response = b"* SEARCH".to_vec();
+            for message in found.iter() {
+                response.extend(format!(" {}", message.uid).as_bytes());
+            }
+            // RFC 7162 3.1.5 - the highest modseq of the matches follows when searching by MODSEQ
+            if let (Some(_), Some(highest)) = (modseq, found.iter().map(|m| m.modseq).max()) {
+                response.extend(format!(" (MODSEQ {})", highest).as_bytes());
+            }
+            out.extend(response);
+            out.extend(b"\r\n");
+        }
+        "FETCH" => {
+            let items = args
+                .get(2)
+                .map(|i| i.to_ascii_uppercase())
+                .unwrap_or_default();
+            let items: Vec<&str> = items
+                .trim_start_matches('(')
+                .trim_end_matches(')')
+                .split_whitespace()
+                .collect();
+            let gmail = state.capabilities.split(' ').any(|c| c == "X-GM-EXT-1");
+            if !gmail && items.iter().any(|i| i.starts_with("X-GM-")) {
+                out.extend(format!("{} BAD Unknown FETCH attribute\r\n", tag).as_bytes());
+                return;
+            }
+            // (CHANGEDSINCE <modseq> [VANISHED]) - only what changed since, with the MODSEQ
+            let modifiers = args.get(3).map(|m| parse_list(m)).unwrap_or_default();
+            let changed_since = modifiers
+                .iter()
+                .position(|m| m.eq_ignore_ascii_case("CHANGEDSINCE"))
+                .and_then(|index| modifiers.get(index + 1))
+                .and_then(|m| m.parse::<u64>().ok());
+            let mut items = items;
+            if changed_since.is_some() && !items.contains(&"MODSEQ") {
+                items.push("MODSEQ");
+            }
+            for (index, message) in state.mailboxes[selected].messages.iter().enumerate() {
+                if in_set(&set, message.uid) && changed_since.map_or(true, |m| message.modseq > m) {
+                    out.extend(fetch_response(index + 1, message, &items));
+                }
+            }
+        }
+        "STORE" => {
+            let action = args
+                .get(2)
+                .map(|a| a.to_ascii_uppercase())
+                .unwrap_or_default();
+            let flags = args.get(3).map(|f| parse_list(f)).unwrap_or_default();
+            let MockMailbox {
+                messages,
+                highest_modseq,
+                ..
+            } = state.mailboxes.get_mut(selected).unwrap();
+            for message in messages.iter_mut() {
+                if !in_set(&set, message.uid) {
+                    continue;
+                }
+                *highest_modseq += 1;
+                message.modseq = *highest_modseq;
+                match action.trim_end_matches(".SILENT") {
+                    "+FLAGS" => {
+                        for flag in flags.iter() {
+                            if !message.flags.contains(flag) {
+                                message.flags.push(flag.clone());
+                            }
+                        }
+                    }
+                    "-FLAGS" => message.flags.retain(|f| !flags.contains(f)),
+                    _ => message.flags = flags.clone(),
+                }
+            }
+        }
+        "COPY" | "MOVE" => {
+            let destination = match args.get(2).filter(|d| state.mailboxes.contains_key(*d)) {
+                Some(destination) => destination.clone(),
+                None => {
+                    out.extend(format!("{} NO [TRYCREATE] No such mailbox\r\n", tag).as_bytes());
+                    return;
+                }
+            };
+            let messages: Vec<MockMessage> = state.mailboxes[selected]
+                .messages
+                .iter()
+                .filter(|m| in_set(&set, m.uid))
+                .cloned()
+                .collect();
+            for message in messages.iter() {
+                state
+                    .mailboxes
+                    .get_mut(&destination)
+                    .unwrap()
+                    .append(message.raw.clone(), message.flags.clone());
+            }
+            if command == "MOVE" {
+                expunge(state, selected, out, |m| in_set(&set, m.uid));
+            }
+        }
+        "EXPUNGE" => expunge(state, selected, out, |m| {
+            in_set(&set, m.uid) && m.flags.iter().any(|f| f == "\\Deleted")
+        }),
+        _ => {
+            out.extend(format!("{} BAD Unknown UID command\r\n", tag).as_bytes());
+            return;
+        }
+    }
+    out.extend(format!("{} OK UID {} completed\r\n", tag, command).as_bytes());
+}
+
+fn expunge(
+    state: &mut MockState,
+    selected: &str,
+    out: &mut Vec<u8>,
+    remove: impl Fn(&MockMessage) -> bool,
+) {
+    let mailbox = state.mailboxes.get_mut(selected).unwrap();
+    // Sequence numbers shift with every expunge - report from the highest down
+    for index in (0..mailbox.messages.len()).rev() {
+        if remove(&mailbox.messages[index]) {
+            mailbox.messages.remove(index);
+            mailbox.next_modseq();
+            out.extend(format!("* {} EXPUNGE\r\n", index + 1).as_bytes());
+        }
+    }
+}
+
+fn fetch_response(seq: usize, message: &MockMessage, items: &[&str]) -> Vec<u8> {
+    let (header, text) = split_header(&message.raw);
+    let mut out = format!("* {} FETCH (UID {}", seq, message.uid).into_bytes();
+    for item in items {
+        let literal = |out: &mut Vec<u8>, name: &str, bytes: &[u8]| {
+            out.extend(format!(" {} {{{}}}\r\n", name, bytes.len()).as_bytes());
+            out.extend(bytes);
+        };
+        match *item {
+            "FLAGS" => out.extend(format!(" FLAGS ({})", message.flags.join(" ")).as_bytes()),
+            "RFC822.SIZE" => out.extend(format!(" RFC822.SIZE {}", message.raw.len()).as_bytes()),
+            "MODSEQ" => out.extend(format!(" MODSEQ ({})", message.modseq).as_bytes()),
+            "INTERNALDATE" => out.extend(format!(" INTERNALDATE \"{}\"", INTERNALDATE).as_bytes()),
+            "RFC822" => literal(&mut out, "RFC822", &message.raw),
+            "BODY[]" | "BODY.PEEK[]" => literal(&mut out, "BODY[]", &message.raw),
+            "RFC822.HEADER" => literal(&mut out, "RFC822.HEADER", header),
+            "RFC822.TEXT" => literal(&mut out, "RFC822.TEXT", text),
+            // Made up from the UID - the same message always gets the same ids
+            "X-GM-MSGID" => out.extend(format!(" X-GM-MSGID {}", 1000 + message.uid).as_bytes()),
+            "X-GM-THRID" => out.extend(format!(" X-GM-THRID {}", 2000 + message.uid).as_bytes()),
+            "X-GM-LABELS" => out.extend(b" X-GM-LABELS (\\Inbox \"Mock label\")"),
+            "ENVELOPE" => {
+                out.extend(b" ENVELOPE ");
+                out.extend(envelope(&message.raw));
+            }
+            "BODYSTRUCTURE" => {
+                out.extend(b" BODYSTRUCTURE ");
+                out.extend(body_structure(&message.raw));
+            }
+            _ => {}
+        }
+    }
+    out.extend(b")\r\n");
+    out
+}
+
+// ENVELOPE the way servers build it - date, subject & ids as found in the header, the addresses parsed
+fn envelope(raw: &[u8]) -> Vec<u8> {
+    let header = |name: &str| {
+        crate::record::raw_header_values(raw, name)
+            .into_iter()
+            .next()
+    };
+    let message = mail_parser::MessageParser::default().parse_headers(raw);
+    let addresses = |address: Option<&Address>| match address {
+        Some(address) => address_list(address),
+        None => b"NIL".to_vec(),
+    };
+
+    let from = addresses(message.as_ref().and_then(|m| m.from()));
+    // Sender & Reply-To default to From
+    let sender = match message.as_ref().and_then(|m| m.sender()) {
+        Some(sender) => address_list(sender),
+        None => from.clone(),
+    };
+    let reply_to = match message.as_ref().and_then(|m| m.reply_to()) {
+        Some(reply_to) => address_list(reply_to),
+        None => from.clone(),
+    };
+
+    let fields = [
+        nstring(header("Date").as_deref()),
+        nstring(header("Subject").as_deref()),
+        from,
+        sender,
+        reply_to,
+        addresses(message.as_ref().and_then(|m| m.to())),
+        addresses(message.as_ref().and_then(|m| m.cc())),
+        addresses(message.as_ref().and_then(|m| m.bcc())),
+        nstring(header("In-Reply-To").as_deref()),
+        nstring(header("Message-ID").as_deref()),
+    ];
+    let mut out = b"(".to_vec();
+    out.extend(fields.join(&b' '));
+    out.push(b')');
+    out
+}
+
+// BODYSTRUCTURE with the extension data - the MIME headers parsed, multiparts split at their boundaries
+fn body_structure(raw: &[u8]) -> Vec<u8> {
+    let (header, body) = match raw.starts_with(b"\r\n") {
+        true => raw.split_at(2),
+        false => split_header(raw),
+    };
+    let header_value = |name: &str| {
+        crate::record::raw_header_values(header, name)
+            .into_iter()
+            .next()
+    };
+    let message = mail_parser::MessageParser::default().parse_headers(header);
+    let content_type = message.as_ref().and_then(|m| m.content_type());
+    let (ty, subtype) = match content_type {
+        Some(content_type) => (
+            content_type.c_type.to_ascii_uppercase(),
+            content_type
+                .c_subtype
+                .as_ref()
+                .map_or("PLAIN".to_string(), |s| s.to_ascii_uppercase()),
+        ),
+        None => ("TEXT".to_string(), "PLAIN".to_string()),
+    };
+    let params = match content_type {
+        Some(content_type) => body_params(content_type.attributes.as_deref()),
+        // RFC 2045 5.2 defaults
+        None => b"(\"CHARSET\" \"us-ascii\")".to_vec(),
+    };
+    let disposition = match message.as_ref().and_then(|m| m.content_disposition()) {
+        Some(disposition) => {
+            let mut out = b"(".to_vec();
+            out.extend(nstring(Some(disposition.c_type.as_ref())));
+            out.push(b' ');
+            out.extend(body_params(disposition.attributes.as_deref()));
+            out.push(b')');
+            out
+        }
+        None => b"NIL".to_vec(),
+    };
+    let language = match message.as_ref().map(|m| m.content_language()) {
+        Some(HeaderValue::Text(language)) => nstring(Some(language.as_ref())),
+        Some(HeaderValue::TextList(languages)) => {
+            let languages: Vec<Vec<u8>> = languages
+                .iter()
+                .map(|l| nstring(Some(l.as_ref())))
+                .collect();
+            let mut out = b"(".to_vec();
+            out.extend(languages.join(&b' '));
+            out.push(b')');
+            out
+        }
+        _ => b"NIL".to_vec(),
+    };
+    let location = nstring(header_value("Content-Location").as_deref());
+
+    let mut out = b"(".to_vec();
+    if ty == "MULTIPART" {
+        let boundary = content_type.and_then(|c| c.attribute("boundary"));
+        for part in boundary.map_or(vec![], |b| split_multipart(body, b)) {
+            out.extend(body_structure(part));
+        }
+        out.push(b' ');
+        out.extend(
+            [
+                nstring(Some(&subtype)),
+                params,
+                disposition,
+                language,
+                location,
+            ]
+            .join(&b' '),
+        );
+        out.push(b')');
+        return out;
+    }
+
+    let encoding = header_value("Content-Transfer-Encoding").unwrap_or("7BIT".to_string());
+    let mut fields = vec![
+        nstring(Some(&ty)),
+        nstring(Some(&subtype)),
+        params,
+        nstring(header_value("Content-ID").as_deref()),
+        nstring(header_value("Content-Description").as_deref()),
+        nstring(Some(&encoding)),
+        body.len().to_string().into_bytes(),
+    ];
+    // A last line without CRLF counts as well
+    let lines = body.iter().filter(|b| **b == b'\n').count()
+        + usize::from(!body.is_empty() && !body.ends_with(b"\n"));
+    let lines = lines.to_string();
+    if ty == "MESSAGE" && subtype == "RFC822" {
+        fields.push(envelope(body));
+        fields.push(body_structure(body));
+        fields.push(lines.into_bytes());
+    } else if ty == "TEXT" {
+        fields.push(lines.into_bytes());
+    }
+    fields.extend([b"NIL".to_vec(), disposition, language, location]);
+    out.extend(fields.join(&b' '));
+    out.push(b')');
+    out
+}
+
+fn body_params(attributes: Option<&[(Cow<str>, Cow<str>)]>) -> Vec<u8> {
+    match attributes {
+        Some(attributes) if !attributes.is_empty() => {
+            let attributes: Vec<Vec<u8>> = attributes
+                .iter()
+                .flat_map(|(name, value)| {
+                    [nstring(Some(name.as_ref())), nstring(Some(value.as_ref()))]
+                })
+                .collect();
+            let mut out = b"(".to_vec();
+            out.extend(attributes.join(&b' '));
+            out.push(b')');
+            out
+        }
+        _ => b"NIL".to_vec(),
+    }
+}
+
+// The body parts between the delimiter lines - the CRLF before a delimiter belongs to it
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut start = None;
+    let mut offset = 0;
+    for line in body.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(delimiter.as_bytes()) {
+            if let Some(start) = start {
+                let end = match body[..offset].ends_with(b"\r\n") {
+                    true => offset - 2,
+                    false => offset.saturating_sub(1),
+                };
+                parts.push(&body[start..end.max(start)]);
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                break;
+            }
+            start = Some(offset + line.len());
+        }
+        offset += line.len();
+    }
+    parts
+}
+
+// Groups are framed by (NIL NIL "group" NIL) ... (NIL NIL NIL NIL)
+fn address_list(address: &Address) -> Vec<u8> {
+    let address_part = |name: Option<&str>, email: Option<&str>| {
+        let (mailbox, host) = match email.and_then(|e| e.rsplit_once('@')) {
+            Some((mailbox, host)) => (Some(mailbox), Some(host)),
+            None => (email, None),
+        };
+        let mut out = b"(".to_vec();
+        out.extend(
+            [
+                nstring(name),
+                b"NIL".to_vec(),
+                nstring(mailbox),
+                nstring(host),
+            ]
+            .join(&b' '),
+        );
+        out.push(b')');
+        out
+    };
+
+    let mut parts = vec![];
+    match address {
+        Address::List(list) => {
+            for addr in list {
+                parts.push(address_part(addr.name.as_deref(), addr.address.as_deref()));
+            }
+        }
+        Address::Group(groups) => {
+            for group in groups {
+                if let Some(name) = &group.name {
+                    parts.push(address_part(None, Some(name)));
+                }
+                for addr in group.addresses.iter() {
+                    parts.push(address_part(addr.name.as_deref(), addr.address.as_deref()));
+                }
+                if group.name.is_some() {
+                    parts.push(address_part(None, None));
+                }
+            }
+        }
+    }
+    match parts.is_empty() {
+        true => b"NIL".to_vec(),
+        false => {
+            let mut out = b"(".to_vec();
+            out.extend(parts.concat());
+            out.push(b')');
+            out
+        }
+    }
+}
+
+// Quoted when possible, a literal otherwise
+fn nstring(value: Option<&str>) -> Vec<u8> {
+    match value {
+        None => b"NIL".to_vec(),
+        Some(value) if value.is_ascii() && !value.contains(['\r', '\n']) => {
+            format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")).into_bytes()
+        }
+        Some(value) => {
+            let mut out = format!("{{{}}}\r\n", value.len()).into_bytes();
+            out.extend(value.as_bytes());
+            out
+        }
+    }
+}
+
+fn split_header(raw: &[u8]) -> (&[u8], &[u8]) {
+    match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(end) => raw.split_at(end + 4),
+        None => (raw, &raw[raw.len()..]),
+    }
+}
+
+// e.g. 1:5,7,9:* - * being the highest UID
+fn parse_set(set: &str, uid_next: u32) -> Vec<(u32, u32)> {
+    let highest = uid_next.saturating_sub(1);
+    let number = |n: &str| match n {
+        "*" => Some(highest),
+        n => n.parse::<u32>().ok(),
+    };
+    set.split(',')
+        .filter_map(|range| match range.split_once(':') {
+            Some((start, end)) => {
+                let (start, end) = (number(start)?, number(end)?);
+                Some((start.min(end), start.max(end)))
+            }
+            None => number(range).map(|n| (n, n)),
+        })
+        .collect()
+}
+
+fn in_set(set: &[(u32, u32)], uid: u32) -> bool {
+    set.iter()
+        .any(|(start, end)| (*start..=*end).contains(&uid))
+}
+
+fn parse_list(list: &str) -> Vec<String> {
+    list.trim_start_matches('(')
+        .trim_end_matches(')')
+        .split_whitespace()
+        .map(|f| f.to_string())
+        .collect()
+}
+
+fn has_capability(state: &MockState, capability: &str) -> bool {
+    state
+        .capabilities
+        .split(' ')
+        .any(|c| c.eq_ignore_ascii_case(capability))
+}
+
+// Only * and a trailing % are needed here
+fn wildcard_matches(pattern: &str, mailbox: &str) -> bool {
+    match pattern.strip_suffix(['*', '%']) {
+        Some(prefix) => mailbox.starts_with(prefix),
+        None => pattern == mailbox,
+    }
+}
+
+// Atoms, quoted strings unquoted and parenthesized lists kept whole
+fn tokenize(command: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => token.extend(chars.next()),
+                        '"' => break,
+                        c => token.push(c),
+                    }
+                }
+                tokens.push(token);
+            }
+            '(' => {
+                let mut token = String::from(c);
+                let mut depth = 1;
+                for c in chars.by_ref() {
+                    token.push(c);
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => {}
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                tokens.push(token);
+            }
+            c => {
+                let mut token = String::from(c);
+                while let Some(c) = chars.next_if(|c| *c != ' ') {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
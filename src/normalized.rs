@@ -0,0 +1,227 @@
+use async_imap::types::Fetch;
+use mail_parser::{Addr, Address, HeaderValue, MimeHeaders, PartType};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Bumped on any incompatible change of NormalizedEmail - see schema/normalized-email-v1.json
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+// Stable shape of the message modelled on the JMAP Email object (RFC 8621)
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NormalizedEmail {
+    // snake_case as the fields of the record - only the JMAP properties are camelCase
+    #[serde(rename = "schema_version")]
+    pub schema_version: u32,
+    pub message_id: Vec<String>,
+    pub in_reply_to: Vec<String>,
+    pub references: Vec<String>,
+    pub from: Vec<EmailAddress>,
+    pub sender: Vec<EmailAddress>,
+    pub reply_to: Vec<EmailAddress>,
+    pub to: Vec<EmailAddress>,
+    pub cc: Vec<EmailAddress>,
+    pub bcc: Vec<EmailAddress>,
+    pub subject: Option<String>,
+    pub sent_at: Option<String>,
+    pub received_at: Option<String>,
+    pub size: Option<u32>,
+    pub text_body: Option<String>,
+    pub html_body: Option<String>,
+    pub attachments: Vec<EmailAttachment>,
+    pub headers: Vec<EmailHeader>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct EmailAddress {
+    pub name: Option<String>,
+    pub email: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EmailAttachment {
+    pub part_id: String,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub disposition: Option<String>,
+    pub cid: Option<String>,
+    pub size: usize,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+// From the full message, or only the header when that is all that was fetched
+pub(crate) fn normalize(item: &Fetch) -> Option<NormalizedEmail> {
+    let raw = item.body().or_else(|| item.header())?;
+    let message = mail_parser::MessageParser::default().parse(raw)?;
+
+    let text_body = body_text(&message, &message.text_body, false);
+    let html_body = body_text(&message, &message.html_body, true);
+
+    let attachments = message
+        .attachments
+        .iter()
+        .filter_map(|part_id| message.parts.get(*part_id).map(|part| (part_id, part)))
+        .map(|(part_id, part)| EmailAttachment {
+            part_id: part_id.to_string(),
+            name: part.attachment_name().map(|name| name.to_string()),
+            content_type: crate::attachment::content_type(part),
+            disposition: part
+                .content_disposition()
+                .map(|disposition| disposition.ctype().to_lowercase()),
+            cid: part.content_id().map(|cid| cid.to_string()),
+            size: part.contents().len(),
+            sha256: crate::util::hex_encode(&Sha256::digest(part.contents())),
+        })
+        .collect();
+
+    let headers = message
+        .headers()
+        .iter()
+        .map(|header| EmailHeader {
+            name: header.name.as_str().to_string(),
+            value: raw
+                .get(header.offset_start..header.offset_end)
+                .map(crate::record::unfold)
+                .unwrap_or_default(),
+        })
+        .collect();
+
+    Some(NormalizedEmail {
+        schema_version: SCHEMA_VERSION,
+        message_id: message
+            .message_id()
+            .map(|id| vec![id.to_string()])
+            .unwrap_or_default(),
+        in_reply_to: text_list(message.in_reply_to()),
+        references: text_list(message.references()),
+        from: addresses(message.from()),
+        sender: addresses(message.sender()),
+        reply_to: addresses(message.reply_to()),
+        to: addresses(message.to()),
+        cc: addresses(message.cc()),
+        bcc: addresses(message.bcc()),
+        subject: message.subject().map(|subject| subject.to_string()),
+        sent_at: message.date().map(|date| date.to_rfc3339()),
+        received_at: item.internal_date().map(|date| date.to_rfc3339()),
+        size: item.size,
+        text_body,
+        html_body,
+        attachments,
+        headers,
+    })
+}
+
+// The text/plain or text/html parts of the body joined - mail_parser may list the other kind as a fallback
+fn body_text(message: &mail_parser::Message, part_ids: &[usize], html: bool) -> Option<String> {
+    let texts: Vec<&str> = part_ids
+        .iter()
+        .filter_map(|part_id| message.parts.get(*part_id))
+        .filter_map(|part| match (&part.body, html) {
+            (PartType::Text(text), false) | (PartType::Html(text), true) => Some(text.as_ref()),
+            _ => None,
+        })
+        .collect();
+    match texts.is_empty() {
+        true => None,
+        false => Some(texts.join("\n")),
+    }
+}
+
+fn text_list(value: &HeaderValue) -> Vec<String> {
+    match value.as_text_list() {
+        Some(list) => list.iter().map(|text| text.to_string()).collect(),
+        None => vec![],
+    }
+}
+
+fn addresses(address: Option<&Address>) -> Vec<EmailAddress> {
+    let addrs: Vec<&Addr> = match address {
+        Some(Address::List(list)) => list.iter().collect(),
+        Some(Address::Group(groups)) => groups
+            .iter()
+            .flat_map(|group| group.addresses.iter())
+            .collect(),
+        None => vec![],
+    };
+    addrs
+        .into_iter()
+        .filter_map(|addr| {
+            Some(EmailAddress {
+                name: addr.name.as_ref().map(|name| name.to_string()),
+                email: addr.address.as_ref()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const FETCH: &str = "(UID INTERNALDATE RFC822.SIZE RFC822)";
+
+    fn schema() -> Value {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("schema/normalized-email-v1.json");
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    // Every property of the schema object is output and nothing else - the schema requires all of them
+    fn assert_properties(value: &Value, schema: &Value) {
+        let mut names: Vec<&String> = value.as_object().unwrap().keys().collect();
+        let mut properties: Vec<&String> =
+            schema["properties"].as_object().unwrap().keys().collect();
+        let mut required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|name| name.as_str().unwrap())
+            .collect();
+        names.sort();
+        properties.sort();
+        required.sort();
+        assert_eq!(names, properties);
+        assert_eq!(names, required);
+        assert_eq!(schema["additionalProperties"], false);
+    }
+
+    #[async_std::test]
+    async fn matches_schema() {
+        let raw = std::fs::read(
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/multipart_mixed.eml"),
+        )
+        .unwrap();
+        let item = crate::mock_imap::fetch_message(&raw, &[], FETCH).await;
+        let normalized = serde_json::to_value(normalize(&item).unwrap()).unwrap();
+        let schema = schema();
+
+        assert_properties(&normalized, &schema);
+        assert_eq!(
+            normalized["schema_version"],
+            schema["properties"]["schema_version"]["const"]
+        );
+        assert_eq!(normalized["schema_version"], SCHEMA_VERSION);
+
+        let address = &normalized["from"][0];
+        assert_eq!(address["email"], "alice@example.com");
+        assert_properties(address, &schema["$defs"]["addressList"]["items"]);
+
+        let attachment = &normalized["attachments"][0];
+        assert_eq!(attachment["type"], "text/csv");
+        assert_properties(attachment, &schema["$defs"]["attachment"]);
+
+        let header = &normalized["headers"][0];
+        assert_eq!(header["name"], "Return-Path");
+        assert_properties(header, &schema["properties"]["headers"]["items"]);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::ImapConfig;
+use anyhow::{anyhow, Result};
+use fluvio::{Compression, Fluvio, TopicProducer, TopicProducerConfigBuilder};
+use fluvio_connector_common::tracing::info;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Producers of the topics besides the connector topic i.e. mailbox_topic & dead_letter_topic.
+// Fluvio is only connected to once the first record goes to one of them - most deployments never do
+pub(crate) struct TopicProducers {
+    dead_letter_topic: Option<String>,
+    mailbox_topic: Option<String>,
+    linger: Option<Duration>,
+    batch_size: Option<usize>,
+    compression: Option<Compression>,
+    fluvio: Option<Fluvio>,
+    producers: HashMap<String, TopicProducer>,
+}
+
+impl TopicProducers {
+    pub(crate) fn new(config: &ImapConfig) -> Result<Self> {
+        let settings = config.topic_producer.clone().unwrap_or_default();
+        let compression = match &settings.compression {
+            Some(compression) => Some(compression.parse::<Compression>().map_err(|e| {
+                anyhow!(
+                    "Invalid topic_producer compression {}: {:?}",
+                    compression,
+                    e
+                )
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            dead_letter_topic: config.dead_letter_topic.clone(),
+            mailbox_topic: config.mailbox_topic.clone(),
+            linger: settings.linger_ms.map(Duration::from_millis),
+            batch_size: settings.batch_size,
+            compression,
+            fluvio: None,
+            producers: HashMap::new(),
+        })
+    }
+
+    // The topic of the record when it does not go to the connector topic
+    pub(crate) fn topic(&self, mailbox: &str, error: bool) -> Option<String> {
+        match (&self.dead_letter_topic, &self.mailbox_topic) {
+            (Some(dead_letter_topic), _) if error => Some(dead_letter_topic.clone()),
+            (_, Some(template)) => Some(crate::imap_util::mailbox_topic_name(template, mailbox)),
+            (_, None) => None,
+        }
+    }
+
+    pub(crate) async fn producer(&mut self, topic: &str) -> Result<&TopicProducer> {
+        if !self.producers.contains_key(topic) {
+            let fluvio = match &mut self.fluvio {
+                Some(fluvio) => fluvio,
+                fluvio => fluvio.insert(Fluvio::connect().await?),
+            };
+
+            let mut builder = TopicProducerConfigBuilder::default();
+            if let Some(linger) = self.linger {
+                builder = builder.linger(linger);
+            }
+            if let Some(batch_size) = self.batch_size {
+                builder = builder.batch_size(batch_size);
+            }
+            if let Some(compression) = self.compression {
+                builder = builder.compression(compression);
+            }
+            let producer = fluvio
+                .topic_producer_with_config(topic, builder.build()?)
+                .await?;
+            info!("Producing records to topic {}", topic);
+            self.producers.insert(topic.to_string(), producer);
+        }
+        Ok(&self.producers[topic])
+    }
+
+    pub(crate) async fn flush(&self) -> Result<()> {
+        for producer in self.producers.values() {
+            producer.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_imap::config;
+
+    #[test]
+    fn record_topics() {
+        let producers = |extra| TopicProducers::new(&config(993, true, extra)).unwrap();
+
+        let connector_only = producers(serde_json::json!({}));
+        assert_eq!(connector_only.topic("INBOX", false), None);
+        assert_eq!(connector_only.topic("INBOX", true), None);
+
+        let dead_letter = producers(serde_json::json!({"dead_letter_topic": "mail-errors"}));
+        assert_eq!(dead_letter.topic("INBOX", false), None);
+        assert_eq!(
+            dead_letter.topic("INBOX", true),
+            Some("mail-errors".to_string())
+        );
+
+        let both = producers(serde_json::json!({
+            "dead_letter_topic": "mail-errors",
+            "mailbox_topic": "mail-{mailbox}"
+        }));
+        assert_eq!(
+            both.topic("Projects/A", false),
+            Some("mail-projects-a".to_string())
+        );
+        assert_eq!(
+            both.topic("Projects/A", true),
+            Some("mail-errors".to_string())
+        );
+
+        // Error records stay with their mailbox without a dead letter topic
+        let mailbox = producers(serde_json::json!({"mailbox_topic": "mail-{mailbox}"}));
+        assert_eq!(mailbox.topic("INBOX", true), Some("mail-inbox".to_string()));
+    }
+
+    #[test]
+    fn topic_producer_settings() {
+        let producers = |settings| {
+            TopicProducers::new(&config(
+                993,
+                true,
+                serde_json::json!({ "topic_producer": settings }),
+            ))
+        };
+        assert!(producers(serde_json::json!({"compression": "brotli"})).is_err());
+
+        let producers =
+            producers(serde_json::json!({"compression": "gzip", "linger_ms": 50})).unwrap();
+        assert_eq!(producers.linger, Some(Duration::from_millis(50)));
+        assert!(producers.fluvio.is_none());
+    }
+}
@@ -1,10 +1,12 @@
 #[allow(unused_imports)]
 use fluvio_connector_common::tracing::{debug, error, info, trace, warn};
 
-use crate::config::ImapConfig;
-use crate::event::ImapEnvelope;
+use crate::config::{ImapConfig, RecordKeyConfig};
 use crate::event::ImapEvent;
-use async_imap::types::Fetch;
+use crate::event::{
+    AddressPart, AuthResult, DkimResult, DkimVerification, ImapBodyPart, ImapEnvelope,
+};
+use async_imap::types::{Fetch, Flag};
 use msg_auth_status::alloc_yes::MessageAuthStatus;
 use msg_auth_status::alloc_yes::{ReturnPathVerifier, ReturnPathVerifierStatus};
 
@@ -14,18 +16,48 @@ use std::io::{Error, ErrorKind};
 // Fill the ImapEvent record with the FETCH record
 pub(crate) fn fill_record<'msg>(
     config: &ImapConfig,
+    mailbox: &str,
     uid: String,
     item: &'msg Fetch,
     do_dkim_auth: bool,
+    dkim_verification: Option<Vec<DkimVerification>>,
 ) -> Result<ImapEvent<'msg>> {
-    let mut rec = ImapEvent::new(uid);
+    let mut rec = ImapEvent::new(mailbox, uid);
+
+    // Our own verification of the DKIM-Signatures takes the place of Authentication-Results -
+    // only a signature aligned with the sender counts as anyone may sign for a domain of their own
+    let dkim_verified = dkim_verification.is_some();
+    if let Some(dkim_verification) = dkim_verification {
+        let domains = sender_domains(item);
+        rec.dkim_authenticated = Some(dkim_verification.iter().any(|v| {
+            v.result == DkimResult::Pass
+                && v.domain.as_deref().is_some_and(|signing_domain| {
+                    domains.iter().any(|domain| aligned(signing_domain, domain))
+                })
+        }));
+        rec.dkim_verification = Some(dkim_verification);
+    }
+
+    // Only Authentication-Results from our own authserv-ids count when trusted_authserv_ids is set
+    let trusted_headers = item
+        .header()
+        .or_else(|| item.body())
+        .map(|raw| crate::auth_results::trusted_headers(raw, &config.trusted_authserv_ids));
+    if !config.trusted_authserv_ids.is_empty() {
+        rec.auth_results_ignored = trusted_headers.as_ref().map(|(_, ignored)| *ignored);
+    }
+
     if let Some(header) = item.header() {
         if config.mode_parser {
             let parsed = mail_parser::MessageParser::default().parse(header);
             rec.header_parsed = parsed;
         }
-        if do_dkim_auth {
-            let parsed = mail_parser::MessageParser::default().parse(header);
+        if do_dkim_auth && !dkim_verified {
+            let trusted_header = trusted_headers
+                .as_ref()
+                .map(|(trusted, _)| &trusted[..])
+                .unwrap_or(header);
+            let parsed = mail_parser::MessageParser::default().parse(trusted_header);
             if let Some(parsed) = parsed {
                 let auth_status = match MessageAuthStatus::from_mail_parser(&parsed) {
                     Ok(auth_status) => auth_status,
@@ -74,7 +106,10 @@ pub(crate) fn fill_record<'msg>(
     }
     if let Some(body) = &item.body() {
         if config.mode_parser {
-            let parsed = mail_parser::MessageParser::default().parse(*body);
+            let mut parsed = mail_parser::MessageParser::default().parse(*body);
+            if let (Some(parsed), Some(_)) = (&mut parsed, &config.attachments) {
+                crate::attachment::strip_contents(parsed);
+            }
             rec.body_parsed = parsed;
         }
         if config.mode_bytes {
@@ -86,9 +121,33 @@ pub(crate) fn fill_record<'msg>(
         }
     }
 
+    if let Some(text) = &item.text() {
+        if config.mode_bytes {
+            rec.text = Some(text.to_vec());
+        }
+        if config.mode_utf8_lossy {
+            let text_utf8_lossy: String = String::from_utf8_lossy(text).to_string();
+            rec.text_utf8_lossy = Some(text_utf8_lossy);
+        }
+    }
+    if let Some(body_structure) = &item.bodystructure() {
+        let imap_body_structure: ImapBodyPart = (*body_structure).into();
+        rec.body_structure = Some(imap_body_structure);
+    }
+
+    if config.mode_normalized {
+        rec.normalized = crate::normalized::normalize(item);
+    }
+
     if let Some(internal_date) = &item.internal_date() {
         rec.internaldate = Some(internal_date.to_rfc3339());
     }
+    rec.modseq = item.modseq;
+    rec.size = item.size;
+    // FLAGS was asked for even when the message has none
+    if crate::imap_util::fetches_flags(&config.fetch) {
+        rec.flags = Some(item.flags().map(|flag| flag_name(&flag)).collect());
+    }
     // Move the mail in case Authenticated destination folder is set
     // and dkim_authenticated == true
     if let Some(dkim_move_to) = &config.dkim_authenticated_move {
@@ -104,5 +163,550 @@ pub(crate) fn fill_record<'msg>(
             rec.moved_to = Some(dkim_move_to.clone());
         }
     }
+
+    if config.mode_auth_results || !config.dmarc_move.is_empty() {
+        if let Some((trusted, _)) = &trusted_headers {
+            let auth_results = crate::auth_results::parse(trusted);
+
+            // Move the mail per the DMARC outcome unless a DKIM move applies already
+            if rec.moved_to.is_none() {
+                rec.moved_to = dmarc_move(config, &auth_results);
+            }
+            if config.mode_auth_results {
+                rec.auth_results = Some(auth_results);
+            }
+        }
+    }
     Ok(rec)
 }
+
+// First dmarc_move matching the DMARC result & policy - a missing DMARC result counts as none
+fn dmarc_move(config: &ImapConfig, auth_results: &[AuthResult]) -> Option<String> {
+    let dmarc = auth_results.iter().find(|r| r.method == "dmarc");
+    let result = dmarc.map(|d| d.result.as_str()).unwrap_or("none");
+    let policy = dmarc.and_then(|d| d.policy.as_deref());
+
+    config
+        .dmarc_move
+        .iter()
+        .find(|m| {
+            m.result
+                .as_ref()
+                .map_or(true, |r| r.eq_ignore_ascii_case(result))
+                && m.policy.as_ref().map_or(true, |p| {
+                    policy.is_some_and(|policy| p.eq_ignore_ascii_case(policy))
+                })
+        })
+        .map(|m| m.mailbox.clone())
+}
+
+// Key the record according to the configured record_key so related mail lands on the same partition
+pub(crate) fn record_key(
+    key_config: &RecordKeyConfig,
+    rec: &ImapEvent,
+    item: &Fetch,
+    uid_validity: Option<u32>,
+) -> Option<String> {
+    let envelope = rec.envelope.as_ref();
+    let uid_validity = uid_validity.map(|v| v.to_string()).unwrap_or_default();
+
+    let key = match key_config {
+        RecordKeyConfig::Uid => Some(rec.uid.clone()),
+        RecordKeyConfig::UidValidityUid => Some(format!("{}:{}", uid_validity, rec.uid)),
+        RecordKeyConfig::MessageId => message_id(envelope, item),
+        RecordKeyConfig::ThreadRoot => thread_root(envelope, item),
+        RecordKeyConfig::Sender => sender_address(envelope, item),
+        RecordKeyConfig::Template(template) => {
+            let subject = envelope.and_then(|e| e.subject.clone());
+            let date = envelope.and_then(|e| e.date.clone());
+            let to = envelope
+                .and_then(|e| e.to.as_ref())
+                .and_then(|to| to.first())
+                .and_then(address_part_email);
+            Some(
+                template
+                    .replace("{mailbox}", &rec.mailbox)
+                    .replace("{uidvalidity}", &uid_validity)
+                    .replace("{uid}", &rec.uid)
+                    .replace(
+                        "{message_id}",
+                        &message_id(envelope, item).unwrap_or_default(),
+                    )
+                    .replace(
+                        "{thread_root}",
+                        &thread_root(envelope, item).unwrap_or_default(),
+                    )
+                    .replace(
+                        "{sender}",
+                        &sender_address(envelope, item).unwrap_or_default(),
+                    )
+                    .replace("{to}", &to.unwrap_or_default())
+                    .replace("{subject}", &subject.unwrap_or_default())
+                    .replace("{date}", &date.unwrap_or_default()),
+            )
+        }
+    };
+
+    if key.is_none() {
+        debug!("No record key {:?} for UID {}", key_config, &rec.uid);
+    }
+    key
+}
+
+// Message-IDs with the surrounding <> removed
+fn strip_msg_id(msg_id: &str) -> Option<String> {
+    let msg_id = msg_id.trim().trim_start_matches('<').trim_end_matches('>');
+    match msg_id.is_empty() {
+        true => None,
+        false => Some(msg_id.to_string()),
+    }
+}
+
+fn parsed_headers(item: &Fetch) -> Option<mail_parser::Message<'_>> {
+    let raw = item.header().or_else(|| item.body())?;
+    mail_parser::MessageParser::default().parse_headers(raw)
+}
+
+// Unfolded raw values of every header with the name - not decoded
+pub(crate) fn raw_header_values(raw: &[u8], name: &str) -> Vec<String> {
+    let parsed = match mail_parser::MessageParser::default().parse_headers(raw) {
+        Some(parsed) => parsed,
+        None => return vec![],
+    };
+    parsed
+        .headers()
+        .iter()
+        .filter(|h| h.name.as_str().eq_ignore_ascii_case(name))
+        .filter_map(|h| raw.get(h.offset_start..h.offset_end))
+        .map(unfold)
+        .collect()
+}
+
+// Raw header value with the line folding removed
+pub(crate) fn unfold(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .replace("\r\n", "")
+        .replace('\n', "")
+        .trim()
+        .to_string()
+}
+
+// Domains of Return-Path and From a DKIM signature has to align with
+fn sender_domains(item: &Fetch) -> Vec<String> {
+    let parsed = match parsed_headers(item) {
+        Some(parsed) => parsed,
+        None => return vec![],
+    };
+    let return_path = parsed.return_path().as_text().map(|a| a.to_string());
+    let from = parsed
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|from| from.address())
+        .map(|a| a.to_string());
+    [return_path, from]
+        .into_iter()
+        .flatten()
+        .filter_map(|address| {
+            let (_, domain) = address.rsplit_once('@')?;
+            Some(domain.trim_end_matches('.').to_lowercase())
+        })
+        .collect()
+}
+
+// Relaxed alignment of RFC 7489 3.1.1 without the public suffix list - the same domain or one below the other
+fn aligned(signing_domain: &str, domain: &str) -> bool {
+    let signing_domain = signing_domain.trim_end_matches('.').to_lowercase();
+    !signing_domain.is_empty()
+        && (domain == signing_domain
+            || domain.ends_with(&format!(".{}", signing_domain))
+            || signing_domain.ends_with(&format!(".{}", domain)))
+}
+
+fn message_id(envelope: Option<&ImapEnvelope>, item: &Fetch) -> Option<String> {
+    if let Some(message_id) = envelope.and_then(|e| e.message_id.as_deref()) {
+        return strip_msg_id(message_id);
+    }
+    parsed_headers(item)?.message_id().and_then(strip_msg_id)
+}
+
+// First of References, otherwise In-Reply-To - the message itself when it starts the thread
+fn thread_root(envelope: Option<&ImapEnvelope>, item: &Fetch) -> Option<String> {
+    if let Some(parsed) = parsed_headers(item) {
+        let root = parsed
+            .references()
+            .as_text_list()
+            .and_then(|references| references.first().and_then(|r| strip_msg_id(r)))
+            .or_else(|| {
+                parsed
+                    .in_reply_to()
+                    .as_text_list()
+                    .and_then(|in_reply_to| in_reply_to.first().and_then(|r| strip_msg_id(r)))
+            });
+        if root.is_some() {
+            return root;
+        }
+    }
+    if let Some(in_reply_to) = envelope.and_then(|e| e.in_reply_to.as_deref()) {
+        if let Some(root) = in_reply_to.split_whitespace().next().and_then(strip_msg_id) {
+            return Some(root);
+        }
+    }
+    message_id(envelope, item)
+}
+
+fn sender_address(envelope: Option<&ImapEnvelope>, item: &Fetch) -> Option<String> {
+    let from = envelope
+        .and_then(|e| e.from.as_ref())
+        .and_then(|from| from.first())
+        .and_then(address_part_email);
+    if from.is_some() {
+        return from;
+    }
+    parsed_headers(item)?
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|from| from.address())
+        .map(|address| address.to_lowercase())
+}
+
+fn address_part_email(address: &AddressPart) -> Option<String> {
+    match (&address.mailbox, &address.host) {
+        (Some(mailbox), Some(host)) => Some(format!("{}@{}", mailbox, host).to_lowercase()),
+        _ => None,
+    }
+}
+
+// IMAP wire form of a flag e.g. \Seen or $Fluvio
+pub(crate) fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(custom) => custom.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::TokenProvider;
+    use crate::mock_imap::MockImapServer;
+    use futures::StreamExt;
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    const FETCH: &str =
+        "(UID FLAGS INTERNALDATE RFC822.SIZE RFC822 RFC822.HEADER RFC822.TEXT ENVELOPE)";
+
+    // Each mode fills its own fields - every mode alone and all of them together cover their combinations
+    const MODES: &[&str] = &[
+        "mode_bytes",
+        "mode_utf8_lossy",
+        "mode_parser",
+        "mode_normalized",
+        "mode_dkim_auth",
+        "mode_auth_results",
+    ];
+
+    fn fixtures_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    // The .eml fixtures by name as FETCHed through async-imap from the mock IMAP server
+    async fn fetch_fixtures() -> Vec<(String, Fetch)> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(fixtures_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "eml") {
+                let name = path.file_stem().unwrap().to_string_lossy().to_string();
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        let server = MockImapServer::start("user", "secret", None).await;
+        for name in names.iter() {
+            let raw = std::fs::read(fixtures_dir().join(format!("{}.eml", name))).unwrap();
+            server.append("INBOX", &raw).await;
+        }
+
+        let config = crate::mock_imap::config(server.port, false, serde_json::json!({}));
+        let client = crate::connection::connect(&config).await.unwrap();
+        let mut session = crate::auth::login(client, &config, &mut TokenProvider::new(None))
+            .await
+            .unwrap();
+        session.select("INBOX").await.unwrap();
+        let fetches: Vec<Fetch> = session
+            .uid_fetch("1:*", FETCH)
+            .await
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect()
+            .await;
+
+        // UIDs are handed out in the order of appending
+        fetches
+            .into_iter()
+            .map(|item| (names[item.uid.unwrap() as usize - 1].clone(), item))
+            .collect()
+    }
+
+    // The record of every mode combination keyed by its name
+    fn records(item: &Fetch) -> Value {
+        let combinations = std::iter::once(("none".to_string(), vec![]))
+            .chain(MODES.iter().map(|mode| (mode.to_string(), vec![*mode])))
+            .chain(std::iter::once(("all".to_string(), MODES.to_vec())));
+
+        let mut records = serde_json::Map::new();
+        for (name, modes) in combinations {
+            let mut extra = serde_json::Map::new();
+            extra.insert("fetch".to_string(), FETCH.into());
+            for mode in MODES {
+                extra.insert(mode.to_string(), modes.contains(mode).into());
+            }
+            let config = crate::mock_imap::config(0, false, Value::Object(extra));
+
+            let uid = item.uid.unwrap().to_string();
+            let record = match fill_record(&config, "INBOX", uid, item, config.mode_dkim_auth, None)
+            {
+                Ok(rec) => serde_json::to_value(&rec).unwrap(),
+                Err(e) => serde_json::json!({ "error": format!("{:#}", e) }),
+            };
+            records.insert(name, record);
+        }
+        Value::Object(records)
+    }
+
+    // Path to the first difference e.g. mode_parser.envelope.from[0].host
+    fn difference(expected: &Value, actual: &Value, path: String) -> Option<String> {
+        match (expected, actual) {
+            (Value::Object(expected), Value::Object(actual)) => {
+                let mut keys = expected.keys().chain(actual.keys());
+                keys.find_map(|key| {
+                    let (e, a) = (expected.get(key), actual.get(key));
+                    match (e, a) {
+                        (Some(e), Some(a)) => difference(e, a, format!("{}.{}", path, key)),
+                        _ => Some(format!("{}.{}: {:?} != {:?}", path, key, e, a)),
+                    }
+                })
+            }
+            (Value::Array(e), Value::Array(a)) if e.len() == a.len() => e
+                .iter()
+                .zip(a)
+                .enumerate()
+                .find_map(|(i, (e, a))| difference(e, a, format!("{}[{}]", path, i))),
+            _ if expected == actual => None,
+            _ => Some(format!("{}: {} != {}", path, expected, actual)),
+        }
+    }
+
+    // Compares against tests/fixtures/<name>.json - UPDATE_GOLDEN=1 writes them for new fixtures
+    // and rewrites them after intended changes, missing ones fail otherwise.
+    #[async_std::test]
+    async fn golden_records() {
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut failures = vec![];
+        for (name, item) in fetch_fixtures().await {
+            let actual = records(&item);
+            let path = fixtures_dir().join(format!("{}.json", name));
+            let expected: Option<Value> = std::fs::read_to_string(&path)
+                .ok()
+                .map(|json| serde_json::from_str(&json).unwrap());
+
+            match expected {
+                Some(expected) if expected == actual => continue,
+                Some(expected) if !update => {
+                    failures.push(difference(&expected, &actual, name).unwrap_or_default());
+                    continue;
+                }
+                None if !update => {
+                    failures.push(format!("{} missing", path.display()));
+                    continue;
+                }
+                _ => {}
+            }
+            let json = serde_json::to_string_pretty(&actual).unwrap();
+            std::fs::write(&path, json + "\n").unwrap();
+            eprintln!("Wrote {}", path.display());
+        }
+        assert!(
+            failures.is_empty(),
+            "Records differ from the golden files - rerun with UPDATE_GOLDEN=1 if intended:\n{}",
+            failures.join("\n")
+        );
+    }
+
+    const REPLY: &str = "From: Alice Example <Alice@Example.COM>\r\n\
+        To: Bob <bob@example.org>\r\n\
+        Subject: Re: Plans\r\n\
+        Date: Fri, 05 Jul 2024 04:26:20 +0200\r\n\
+        Message-ID: <reply-2@example.com>\r\n\
+        In-Reply-To: <parent-1@example.org>\r\n\
+        References: <root-0@example.org>\r\n\
+        \t<parent-1@example.org>\r\n\
+        \r\n\
+        Sounds good.\r\n";
+
+    // The key of the message fetched with fetch - with ENVELOPE the envelope is preferred over the header
+    async fn key(raw: &str, fetch: &str, record_key: &str) -> Option<String> {
+        let config = crate::mock_imap::config(993, false, serde_json::json!({"fetch": fetch}));
+        let item = crate::mock_imap::fetch_message(raw.as_bytes(), &[], fetch).await;
+        let rec = fill_record(&config, "INBOX", "1".to_string(), &item, false, None).unwrap();
+        let key_config = RecordKeyConfig::try_from(record_key.to_string()).unwrap();
+        record_key(&key_config, &rec, &item, Some(42))
+    }
+
+    #[async_std::test]
+    async fn uid_keys() {
+        let fetch = "(UID RFC822.HEADER)";
+        assert_eq!(key(REPLY, fetch, "uid").await.as_deref(), Some("1"));
+        assert_eq!(
+            key(REPLY, fetch, "uidvalidity:uid").await.as_deref(),
+            Some("42:1")
+        );
+        assert_eq!(
+            key(REPLY, fetch, "message-id").await.as_deref(),
+            Some("reply-2@example.com")
+        );
+    }
+
+    #[async_std::test]
+    async fn thread_root_fallbacks() {
+        for fetch in ["(UID RFC822.HEADER)", "(UID RFC822.HEADER ENVELOPE)"] {
+            // References before In-Reply-To
+            assert_eq!(
+                key(REPLY, fetch, "thread-root").await.as_deref(),
+                Some("root-0@example.org")
+            );
+            let no_references = REPLY.replace(
+                "References: <root-0@example.org>\r\n\t<parent-1@example.org>\r\n",
+                "",
+            );
+            assert_eq!(
+                key(&no_references, fetch, "thread-root").await.as_deref(),
+                Some("parent-1@example.org")
+            );
+            // The message itself starts the thread
+            let first = no_references.replace("In-Reply-To: <parent-1@example.org>\r\n", "");
+            assert_eq!(
+                key(&first, fetch, "thread-root").await.as_deref(),
+                Some("reply-2@example.com")
+            );
+        }
+
+        // Only the envelope without the header - its In-Reply-To, then its Message-ID
+        let fetch = "(UID ENVELOPE)";
+        assert_eq!(
+            key(REPLY, fetch, "thread-root").await.as_deref(),
+            Some("parent-1@example.org")
+        );
+        let first = REPLY.replace("In-Reply-To: <parent-1@example.org>\r\n", "");
+        assert_eq!(
+            key(&first, fetch, "thread-root").await.as_deref(),
+            Some("reply-2@example.com")
+        );
+    }
+
+    #[async_std::test]
+    async fn sender_keys() {
+        for fetch in ["(UID RFC822.HEADER)", "(UID ENVELOPE)"] {
+            assert_eq!(
+                key(REPLY, fetch, "sender").await.as_deref(),
+                Some("alice@example.com")
+            );
+        }
+        let no_from = REPLY.replace("From: Alice Example <Alice@Example.COM>\r\n", "");
+        assert_eq!(key(&no_from, "(UID RFC822.HEADER)", "sender").await, None);
+    }
+
+    #[async_std::test]
+    async fn template_keys() {
+        let template = "{mailbox}/{uidvalidity}/{uid}/{message_id}/{thread_root}/{sender}/{to}/{subject}/{date}";
+        assert_eq!(
+            key(REPLY, "(UID RFC822.HEADER ENVELOPE)", template).await.as_deref(),
+            Some("INBOX/42/1/reply-2@example.com/root-0@example.org/alice@example.com/bob@example.org/Re: Plans/Fri, 05 Jul 2024 04:26:20 +0200")
+        );
+        // Values missing without ENVELOPE are left empty, unknown placeholders as they are
+        assert_eq!(
+            key(
+                REPLY,
+                "(UID RFC822.HEADER)",
+                "{sender}|{to}|{subject}|{unknown}"
+            )
+            .await
+            .as_deref(),
+            Some("alice@example.com|||{unknown}")
+        );
+    }
+
+    #[async_std::test]
+    async fn dkim_alignment() {
+        let raw = "Return-Path: <bounces@lists.example.net>\r\n\
+            From: Alice <alice@Example.com>\r\n\
+            Subject: Signed\r\n\
+            \r\n\
+            Hi\r\n";
+        let fetch = "(UID RFC822)";
+        let config = crate::mock_imap::config(
+            993,
+            false,
+            serde_json::json!({"fetch": fetch, "dkim_authenticated_move": "Authenticated"}),
+        );
+        let item = crate::mock_imap::fetch_message(raw.as_bytes(), &[], fetch).await;
+        let verification = |domain: &str, result| DkimVerification {
+            domain: Some(domain.to_string()),
+            selector: Some("s1".to_string()),
+            algorithm: Some("ed25519-sha256".to_string()),
+            result,
+            reason: None,
+        };
+        let authenticated = |verifications: Vec<DkimVerification>| {
+            let rec = fill_record(
+                &config,
+                "INBOX",
+                "1".to_string(),
+                &item,
+                true,
+                Some(verifications),
+            )
+            .unwrap();
+            (rec.dkim_authenticated, rec.moved_to)
+        };
+
+        // A valid signature of someone else's domain vouches for nothing
+        assert_eq!(
+            authenticated(vec![verification("attacker.example", DkimResult::Pass)]),
+            (Some(false), None)
+        );
+        assert_eq!(
+            authenticated(vec![verification("example.com.evil", DkimResult::Pass)]),
+            (Some(false), None)
+        );
+        assert_eq!(
+            authenticated(vec![verification("example.com", DkimResult::Fail)]),
+            (Some(false), None)
+        );
+
+        for domain in [
+            "example.com",
+            "EXAMPLE.com",
+            "mail.example.com",
+            "example.net",
+        ] {
+            assert_eq!(
+                authenticated(vec![
+                    verification("attacker.example", DkimResult::Pass),
+                    verification(domain, DkimResult::Pass),
+                ]),
+                (Some(true), Some("Authenticated".to_string())),
+                "{}",
+                domain
+            );
+        }
+    }
+}
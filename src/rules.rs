@@ -0,0 +1,378 @@
+use crate::actions::MessageAction;
+use crate::config::{RuleActionConfig, RuleCondition, RuleConfig};
+use crate::event::{AddressPart, ImapEvent};
+use anyhow::{Context, Result};
+use async_imap::types::Fetch;
+use regex::Regex;
+
+#[allow(unused_imports)]
+use fluvio_connector_common::tracing::{debug, info, trace, warn};
+
+// Rules compiled once from the configuration
+#[derive(Debug, Default)]
+pub(crate) struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    any: bool,
+    conditions: Vec<Condition>,
+    actions: Vec<RuleActionConfig>,
+    stop: bool,
+}
+
+#[derive(Debug)]
+enum Condition {
+    Header { name: String, regex: Regex },
+    FromDomain(String),
+    ToDomain(String),
+    SizeOver(u32),
+    SizeUnder(u32),
+    Flag(String),
+    DkimAuthenticated(bool),
+    AuthResult { method: String, result: String },
+    HasAttachment(bool),
+}
+
+// What the matching rules decided for a message
+#[derive(Debug, Default)]
+pub(crate) struct RulesOutcome {
+    pub matched: Vec<String>,
+    pub tags: Vec<String>,
+    pub actions: Vec<MessageAction>,
+    pub skip_produce: bool,
+}
+
+impl Rules {
+    pub(crate) fn new(configs: &[RuleConfig]) -> Result<Self> {
+        let mut rules = vec![];
+        for config in configs {
+            let mut conditions = vec![];
+            for condition in config.conditions.iter() {
+                let condition = match condition {
+                    RuleCondition::Header { name, regex } => Condition::Header {
+                        name: name.clone(),
+                        regex: Regex::new(regex).with_context(|| {
+                            format!("Invalid header regex in rule {}", &config.name)
+                        })?,
+                    },
+                    RuleCondition::FromDomain(domain) => Condition::FromDomain(domain.clone()),
+                    RuleCondition::ToDomain(domain) => Condition::ToDomain(domain.clone()),
+                    RuleCondition::SizeOver(size) => Condition::SizeOver(*size),
+                    RuleCondition::SizeUnder(size) => Condition::SizeUnder(*size),
+                    RuleCondition::Flag(flag) => Condition::Flag(flag.clone()),
+                    RuleCondition::DkimAuthenticated(dkim) => Condition::DkimAuthenticated(*dkim),
+                    RuleCondition::AuthResult { method, result } => Condition::AuthResult {
+                        method: method.clone(),
+                        result: result.clone(),
+                    },
+                    RuleCondition::HasAttachment(attachment) => {
+                        Condition::HasAttachment(*attachment)
+                    }
+                };
+                conditions.push(condition);
+            }
+            rules.push(Rule {
+                name: config.name.clone(),
+                any: config.any,
+                conditions,
+                actions: config.actions.clone(),
+                stop: config.stop,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    // Evaluate the rules in order against the message
+    pub(crate) fn evaluate(&self, rec: &ImapEvent, item: &Fetch) -> RulesOutcome {
+        let mut outcome = RulesOutcome::default();
+        let message = MessageView::new(item);
+
+        for rule in self.rules.iter() {
+            let mut results = rule
+                .conditions
+                .iter()
+                .map(|c| condition_matches(c, rec, item, &message));
+            let matched = match rule.any {
+                true => results.any(|r| r),
+                false => results.all(|r| r),
+            };
+            if !matched {
+                continue;
+            }
+            trace!("Rule {} matched UID {}", &rule.name, &rec.uid);
+
+            outcome.matched.push(rule.name.clone());
+            for action in rule.actions.iter() {
+                match action {
+                    RuleActionConfig::Move(mailbox) => {
+                        outcome.actions.push(MessageAction::Move(mailbox.clone()))
+                    }
+                    RuleActionConfig::Copy(mailbox) => {
+                        outcome.actions.push(MessageAction::Copy(mailbox.clone()))
+                    }
+                    RuleActionConfig::Flag(flags) => {
+                        outcome.actions.push(MessageAction::AddFlags(flags.clone()))
+                    }
+                    RuleActionConfig::Tag(tag) => outcome.tags.push(tag.clone()),
+                    RuleActionConfig::SkipProduce => outcome.skip_produce = true,
+                }
+            }
+            if rule.stop {
+                break;
+            }
+        }
+        outcome
+    }
+}
+
+// Access to the fetched message for the conditions
+struct MessageView<'a> {
+    item: &'a Fetch,
+}
+
+impl<'a> MessageView<'a> {
+    fn new(item: &'a Fetch) -> Self {
+        Self { item }
+    }
+
+    fn header_values(&self, name: &str) -> Vec<String> {
+        match self.item.header().or_else(|| self.item.body()) {
+            Some(raw) => crate::record::raw_header_values(raw, name),
+            None => vec![],
+        }
+    }
+
+    fn has_attachment(&self) -> bool {
+        match self.item.body() {
+            Some(body) => mail_parser::MessageParser::default()
+                .parse(body)
+                .is_some_and(|message| message.attachment_count() > 0),
+            None => false,
+        }
+    }
+
+    fn size(&self) -> Option<u32> {
+        self.item
+            .size
+            .or_else(|| self.item.body().map(|body| body.len() as u32))
+    }
+}
+
+fn condition_matches(
+    condition: &Condition,
+    rec: &ImapEvent,
+    item: &Fetch,
+    message: &MessageView,
+) -> bool {
+    let envelope = rec.envelope.as_ref();
+    match condition {
+        Condition::Header { name, regex } => message
+            .header_values(name)
+            .iter()
+            .any(|value| regex.is_match(value)),
+        Condition::FromDomain(domain) => {
+            let from = envelope.and_then(|e| e.from.as_ref());
+            addresses_in_domain(from.into_iter().flatten(), domain)
+        }
+        Condition::ToDomain(domain) => {
+            let to = envelope.and_then(|e| e.to.as_ref());
+            let cc = envelope.and_then(|e| e.cc.as_ref());
+            addresses_in_domain(to.into_iter().chain(cc).flatten(), domain)
+        }
+        Condition::SizeOver(size) => message.size().is_some_and(|s| s > *size),
+        Condition::SizeUnder(size) => message.size().is_some_and(|s| s < *size),
+        Condition::Flag(flag) => item
+            .flags()
+            .any(|f| crate::record::flag_name(&f).eq_ignore_ascii_case(flag)),
+        Condition::DkimAuthenticated(dkim) => rec.dkim_authenticated == Some(*dkim),
+        // Any of the trusted Authentication-Results
+        Condition::AuthResult { method, result } => {
+            rec.auth_results.iter().flatten().any(|auth_result| {
+                auth_result.method.eq_ignore_ascii_case(method)
+                    && auth_result.result.eq_ignore_ascii_case(result)
+            })
+        }
+        Condition::HasAttachment(attachment) => message.has_attachment() == *attachment,
+    }
+}
+
+fn addresses_in_domain<'a>(
+    mut addresses: impl Iterator<Item = &'a AddressPart>,
+    domain: &str,
+) -> bool {
+    addresses.any(|address| {
+        address
+            .host
+            .as_ref()
+            .is_some_and(|host| host.eq_ignore_ascii_case(domain))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{AuthResult, ImapEnvelope};
+    use crate::mock_imap::fetch_message;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    const FETCH: &str = "(UID FLAGS RFC822.SIZE RFC822)";
+    const MESSAGE: &[u8] = b"From: Alice <alice@example.com>\r\n\
+        To: bob@example.org\r\n\
+        Subject: Invoice 42\r\n\
+        List-Id: <news.example.com>\r\n\
+        \r\n\
+        Hi Bob\r\n";
+
+    fn rules(rules: serde_json::Value) -> Rules {
+        Rules::new(&serde_json::from_value::<Vec<RuleConfig>>(rules).unwrap()).unwrap()
+    }
+
+    fn address(mailbox: &str, host: &str) -> AddressPart {
+        AddressPart {
+            mailbox: Some(mailbox.to_string()),
+            host: Some(host.to_string()),
+            ..Default::default()
+        }
+    }
+
+    // As fill_record leaves it with ENVELOPE, mode_dkim_auth and mode_auth_results
+    fn record() -> ImapEvent<'static> {
+        let mut rec = ImapEvent::new("INBOX", "1".to_string());
+        rec.envelope = Some(ImapEnvelope {
+            from: Some(vec![address("alice", "example.com")]),
+            to: Some(vec![address("bob", "Example.ORG")]),
+            ..Default::default()
+        });
+        rec.dkim_authenticated = Some(true);
+        rec.auth_results = Some(vec![AuthResult {
+            authserv_id: "mx.example.org".to_string(),
+            method: "dmarc".to_string(),
+            result: "fail".to_string(),
+            reason: None,
+            domain: Some("example.com".to_string()),
+            selector: None,
+            policy: Some("reject".to_string()),
+            properties: BTreeMap::new(),
+        }]);
+        rec
+    }
+
+    // Names of the rules matching the message
+    async fn matched(rules_config: serde_json::Value, flags: &[&str]) -> Vec<String> {
+        let item = fetch_message(MESSAGE, flags, FETCH).await;
+        rules(rules_config).evaluate(&record(), &item).matched
+    }
+
+    #[async_std::test]
+    async fn all_conditions_unless_any() {
+        let conditions = json!([
+            {"header": {"name": "Subject", "regex": "(?i)invoice"}},
+            {"from_domain": "other.com"},
+        ]);
+        let all = json!([{"name": "all", "conditions": conditions, "actions": []}]);
+        assert!(matched(all, &[]).await.is_empty());
+
+        let any = json!([{"name": "any", "any": true, "conditions": conditions, "actions": []}]);
+        assert_eq!(matched(any, &[]).await, vec!["any"]);
+    }
+
+    #[async_std::test]
+    async fn conditions() {
+        let cases = [
+            (
+                json!({"header": {"name": "list-id", "regex": "^<news\\."}}),
+                true,
+            ),
+            (
+                json!({"header": {"name": "Subject", "regex": "^Hello"}}),
+                false,
+            ),
+            (
+                json!({"header": {"name": "X-Missing", "regex": ".*"}}),
+                false,
+            ),
+            (json!({"from_domain": "EXAMPLE.com"}), true),
+            (json!({"from_domain": "sub.example.com"}), false),
+            (json!({"to_domain": "example.org"}), true),
+            (json!({"size_over": 10}), true),
+            (json!({"size_under": 10}), false),
+            (json!({"flag": "\\flagged"}), true),
+            (json!({"flag": "\\Seen"}), false),
+            (json!({"dkim_authenticated": true}), true),
+            (
+                json!({"auth_result": {"method": "DMARC", "result": "fail"}}),
+                true,
+            ),
+            (
+                json!({"auth_result": {"method": "spf", "result": "fail"}}),
+                false,
+            ),
+            (json!({"has_attachment": false}), true),
+        ];
+        for (condition, expected) in cases {
+            let rule = json!([{"name": "rule", "conditions": [condition], "actions": []}]);
+            let names = matched(rule, &["\\Flagged"]).await;
+            assert_eq!(!names.is_empty(), expected, "{}", condition);
+        }
+    }
+
+    #[async_std::test]
+    async fn stop_skips_following_rules() {
+        let subject = json!([{"header": {"name": "Subject", "regex": "Invoice"}}]);
+        let rules_config = json!([
+            {"name": "first", "conditions": subject, "actions": [{"tag": "first"}]},
+            {"name": "stopping", "conditions": subject, "actions": [], "stop": true},
+            {"name": "skipped", "conditions": subject, "actions": [{"tag": "skipped"}]},
+        ]);
+        assert_eq!(matched(rules_config, &[]).await, vec!["first", "stopping"]);
+    }
+
+    #[async_std::test]
+    async fn outcome_aggregates_matching_rules() {
+        let item = fetch_message(MESSAGE, &[], FETCH).await;
+        let rules = rules(json!([
+            {
+                "name": "invoices",
+                "conditions": [{"header": {"name": "Subject", "regex": "Invoice"}}],
+                "actions": [{"move": "Invoices"}, {"tag": "invoice"}],
+            },
+            {
+                "name": "no match",
+                "conditions": [{"from_domain": "other.com"}],
+                "actions": [{"tag": "other"}, "skip_produce"],
+            },
+            {
+                "name": "newsletters",
+                "conditions": [{"header": {"name": "List-Id", "regex": ".+"}}],
+                "actions": [{"flag": ["\\Seen"]}, {"tag": "newsletter"}, "skip_produce"],
+            },
+        ]));
+
+        let outcome = rules.evaluate(&record(), &item);
+        assert_eq!(outcome.matched, vec!["invoices", "newsletters"]);
+        assert_eq!(outcome.tags, vec!["invoice", "newsletter"]);
+        assert_eq!(
+            outcome.actions,
+            vec![
+                MessageAction::Move("Invoices".to_string()),
+                MessageAction::AddFlags(vec!["\\Seen".to_string()]),
+            ]
+        );
+        assert!(outcome.skip_produce);
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let config = json!([{
+            "name": "broken",
+            "conditions": [{"header": {"name": "Subject", "regex": "("}}],
+            "actions": [],
+        }]);
+        let err = Rules::new(&serde_json::from_value::<Vec<RuleConfig>>(config).unwrap());
+        assert!(err.unwrap_err().to_string().contains("broken"));
+    }
+}
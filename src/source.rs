@@ -1,9 +1,19 @@
-use crate::config::ImapConfig;
-use anyhow::Result;
-use async_imap::Client as AsyncImapClient;
-use async_native_tls::TlsConnector;
+use crate::ack::AckTracker;
+use crate::actions::MessageAction;
+use crate::auth::TokenProvider;
+use crate::checkpoint::Checkpoints;
+use crate::config::{ImapConfig, RuleCondition, TlsMode};
+use crate::connection::ImapStream;
+use crate::dkim::{DkimKeyResolver, DnsKeyResolver};
+use crate::error::ImapError;
+use crate::event::{ImapEvent, ImapRecord};
+use crate::imap_util::{GmailAttributes, SyncExtensions};
+use crate::rules::Rules;
+use anyhow::{bail, Result};
+use async_imap::extensions::idle::Handle as IdleHandle;
+use async_imap::types::Fetch;
+use async_imap::Session as ImapSession;
 use async_std::channel::{self, Sender};
-use async_std::net::TcpStream;
 use async_std::task::spawn;
 use async_trait::async_trait;
 use fluvio::Offset;
@@ -12,7 +22,8 @@ use fluvio_connector_common::tracing::{debug, error, info, trace, warn};
 use fluvio_connector_common::Source;
 use futures::{stream::LocalBoxStream, StreamExt};
 
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
 
 const CHANNEL_BUFFER_SIZE: usize = 10000;
 
@@ -23,139 +34,1368 @@ pub(crate) struct ImapSource {
 
 impl ImapSource {
     pub(crate) fn new(config: ImapConfig) -> Result<Self> {
+        if config.tls_mode == TlsMode::None && !config.allow_insecure_plaintext {
+            bail!("tls_mode none requires allow_insecure_plaintext: true - only use with local test servers");
+        }
+        crate::connection::check_tls_config(&config)?;
+        if config.fetch_batch_size == 0 {
+            bail!("fetch_batch_size must be at least 1");
+        }
+        if config.poll_interval == 0 {
+            bail!("poll_interval must be at least 1 second");
+        }
+        if !config.reconnect.multiplier.is_finite() || !config.reconnect.jitter.is_finite() {
+            bail!("reconnect multiplier and jitter must be finite numbers");
+        }
+        Rules::new(&config.rules)?;
+        // auth_results are only parsed with mode_auth_results - the rule would silently never match
+        if !config.mode_auth_results {
+            let auth_result_rule = config.rules.iter().find(|rule| {
+                rule.conditions
+                    .iter()
+                    .any(|c| matches!(c, RuleCondition::AuthResult { .. }))
+            });
+            if let Some(rule) = auth_result_rule {
+                bail!(
+                    "auth_result condition of rule {} requires mode_auth_results",
+                    &rule.name
+                );
+            }
+        }
+        // Wildcard patterns are checked again once resolved upon connecting
+        let mailboxes: Vec<String> = config
+            .mailbox
+            .patterns()
+            .iter()
+            .filter(|pattern| !pattern.contains(['*', '%']))
+            .cloned()
+            .collect();
+        crate::imap_util::check_mailbox_topics(&config, &mailboxes)?;
+        if config.dkim_verify && !crate::imap_util::fetches_whole_message(&config.fetch) {
+            warn!("dkim_verify requires the full message e.g. RFC822 in fetch - messages without it are not verified.");
+        }
         Ok(Self { config })
     }
 }
 
 #[async_trait]
-impl<'a> Source<'a, String> for ImapSource {
-    async fn connect(self, _offset: Option<Offset>) -> Result<LocalBoxStream<'a, String>> {
+impl<'a> Source<'a, ImapRecord> for ImapSource {
+    async fn connect(self, _offset: Option<Offset>) -> Result<LocalBoxStream<'a, ImapRecord>> {
         info!(
             "IMAP host: {} port {}",
             &self.config.host, &self.config.port
         );
 
         let (sender, receiver) = channel::bounded(CHANNEL_BUFFER_SIZE);
-        spawn(imap_loop(sender, self.clone()));
+        spawn(imap_supervisor(sender, self.clone()));
         Ok(receiver.boxed_local())
     }
 }
 
-async fn imap_loop(tx: Sender<String>, source: ImapSource) -> Result<()> {
-    debug!("Imap loop started");
+// State that must survive the IMAP sessions being rebuilt
+struct SessionState {
+    checkpoints: Checkpoints,
+    tokens: TokenProvider,
+    acks: AckTracker,
+    // Set once a full sync went through - resets the reconnect attempts
+    established: bool,
+}
+
+// Rebuilds both idle and fetch sessions upon errors with exponential backoff
+async fn imap_supervisor(tx: Sender<ImapRecord>, source: ImapSource) {
     let config = source.config;
 
-    let idle_stream = TcpStream::connect(format!("{}:{}", config.host, config.port)).await?;
-    let fetch_stream = TcpStream::connect(format!("{}:{}", config.host, config.port)).await?;
+    let checkpoints = match Checkpoints::load(config.checkpoint_file.as_deref()) {
+        Ok(checkpoints) => checkpoints,
+        Err(e) => {
+            error!("Failed to load checkpoints: {:?}", e);
+            return;
+        }
+    };
+    let rules = match Rules::new(&config.rules) {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("Failed to compile rules: {:?}", e);
+            return;
+        }
+    };
+    let dkim_resolver: Option<Box<dyn DkimKeyResolver>> = match config.dkim_verify {
+        true => match DnsKeyResolver::from_system_conf().await {
+            Ok(resolver) => Some(Box::new(resolver)),
+            Err(e) => {
+                error!("Failed to set up DNS resolver for DKIM: {:?}", e);
+                return;
+            }
+        },
+        false => None,
+    };
+    let mut state = SessionState {
+        checkpoints,
+        tokens: TokenProvider::new(config.auth.clone()),
+        acks: AckTracker::new(),
+        established: false,
+    };
 
-    debug!("TCP TLS Connect");
+    let mut attempt: u32 = 0;
+    loop {
+        let res = imap_loop(&tx, &config, &rules, dkim_resolver.as_deref(), &mut state).await;
 
-    let idle_stream = TlsConnector::new()
-        .use_sni(true)
-        .danger_accept_invalid_certs(config.dangerous_cert)
-        .connect(config.host.clone(), idle_stream)
-        .await
-        .unwrap();
+        if tx.is_closed() {
+            info!("Record channel closed - Stopping IMAP loop.");
+            return;
+        }
 
-    let fetch_stream = TlsConnector::new()
-        .use_sni(true)
-        .danger_accept_invalid_certs(config.dangerous_cert)
-        .connect(config.host.clone(), fetch_stream)
-        .await
-        .unwrap();
+        let err = match res {
+            Ok(()) => {
+                info!("IMAP loop finished.");
+                return;
+            }
+            Err(err) => err,
+        };
+
+        if let Some(imap_err) = err.downcast_ref::<ImapError>() {
+            if !imap_err.is_retryable() {
+                error!("IMAP session failed: {:#} - Not retryable, stopping.", err);
+                return;
+            }
+        }
+
+        if state.established {
+            attempt = 0;
+            state.established = false;
+        }
+        attempt += 1;
+
+        let max_attempts = config.reconnect.max_attempts;
+        if max_attempts > 0 && attempt > max_attempts {
+            error!(
+                "IMAP session failed: {:?} - Giving up after {} reconnect attempts.",
+                err, max_attempts
+            );
+            return;
+        }
+
+        let delay = crate::imap_util::reconnect_delay(&config.reconnect, attempt);
+        warn!(
+            "IMAP session failed: {:?} - Reconnect attempt {} in {:?}",
+            err, attempt, delay
+        );
+        async_std::task::sleep(delay).await;
+    }
+}
+
+// Everything a mailbox sync needs besides the fetch session and checkpoints
+struct SyncContext<'a> {
+    tx: &'a Sender<ImapRecord>,
+    config: &'a ImapConfig,
+    rules: &'a Rules,
+    dkim_resolver: Option<&'a dyn DkimKeyResolver>,
+    sync_ext: SyncExtensions,
+    do_dkim_auth: bool,
+}
+
+async fn imap_loop(
+    tx: &Sender<ImapRecord>,
+    config: &ImapConfig,
+    rules: &Rules,
+    dkim_resolver: Option<&dyn DkimKeyResolver>,
+    state: &mut SessionState,
+) -> Result<()> {
+    debug!("Imap loop started");
+
+    let idle_client = crate::connection::connect(config).await?;
+    let fetch_client = crate::connection::connect(config).await?;
 
     info!("Async IMAP Client Initialize");
 
-    let idle_client = AsyncImapClient::new(idle_stream);
-    let fetch_client = AsyncImapClient::new(fetch_stream);
+    let mut idle_session = crate::auth::login(idle_client, config, &mut state.tokens).await?;
+    let mut fetch_session = crate::auth::login(fetch_client, config, &mut state.tokens).await?;
 
-    let mut idle_session = idle_client
-        .login(config.user.clone(), config.password.clone())
-        .await
-        .map_err(|(err, _client)| err)?;
+    let do_dkim_auth = crate::imap_util::check_config(config, &mut fetch_session).await?;
 
-    let mut fetch_session = fetch_client
-        .login(config.user.clone(), config.password.clone())
-        .await
-        .map_err(|(err, _client)| err)?;
+    let sync_ext = crate::imap_util::enable_sync_extensions(&mut fetch_session).await?;
 
-    let do_dkim_auth = crate::imap_util::check_config(&config, &mut fetch_session).await?;
+    let mailboxes = crate::imap_util::resolve_mailboxes(config, &mut fetch_session).await?;
+    info!("IMAP Watching Mailboxes {:?}", &mailboxes);
 
-    let idle_inbox = idle_session.select(&config.mailbox).await?;
+    let idle_inbox =
+        idle_session
+            .select(&mailboxes[0])
+            .await
+            .map_err(|source| ImapError::Select {
+                mailbox: mailboxes[0].clone(),
+                source,
+            })?;
 
-    info!("IMAP Connecting to Mailbox {}", &config.mailbox);
+    info!("IMAP Connecting to Mailbox {}", &mailboxes[0]);
     debug!("IMAP idle_inbox cur = {:?}", idle_inbox);
 
-    let mut idle_handle = idle_session.idle();
-    idle_handle.init().await?;
+    let idle = crate::imap_util::supports_idle(&mut idle_session).await?;
+    if !idle {
+        info!("Server does not support IDLE.");
+    }
+    // IDLE only tells about the selected mailbox - the others need NOTIFY or else idling would starve them
+    let notify = idle
+        && (mailboxes.len() == 1
+            || crate::imap_util::enable_notify(&mut idle_session, &mailboxes).await?);
+
+    let mut watch = match notify {
+        true => {
+            info!("Server supports IDLE - Waiting for new mail via IDLE.");
+            let mut idle_handle = idle_session.idle();
+            idle_handle
+                .init()
+                .await
+                .map_err(|source| ImapError::Idle { source })?;
+            Watch::Idle(idle_handle)
+        }
+        false => {
+            info!("Polling every {} seconds.", config.poll_interval);
+            Watch::Poll(idle_session)
+        }
+    };
+
+    let ctx = SyncContext {
+        tx,
+        config,
+        rules,
+        dkim_resolver,
+        sync_ext,
+        do_dkim_auth,
+    };
+
+    let mut to_sync = mailboxes.clone();
+    loop {
+        // Mailboxes with acknowledged messages still awaiting their actions are due as well
+        for mailbox in state.acks.mailboxes_with_acked() {
+            if !to_sync.contains(&mailbox) {
+                to_sync.push(mailbox);
+            }
+        }
+        for mailbox in to_sync.iter() {
+            sync_mailbox(&ctx, &mut fetch_session, state, mailbox).await?;
+        }
+        // Failures persisting past the login e.g. upon a particular message still run out of attempts
+        state.established = true;
+
+        match &mut watch {
+            Watch::Idle(idle_handle) => wait_idle(idle_handle, config, &mailboxes).await?,
+            Watch::Poll(idle_session) => {
+                async_std::task::sleep(Duration::from_secs(config.poll_interval)).await;
+                // Keeps the otherwise unused session from timing out
+                idle_session
+                    .noop()
+                    .await
+                    .map_err(|source| ImapError::Noop { source })?;
+            }
+        }
+
+        // Only a single mailbox is left to the CONDSTORE / search checks, otherwise ask STATUS which have changed
+        to_sync = match mailboxes.len() {
+            1 => mailboxes.clone(),
+            _ => {
+                crate::imap_util::changed_mailboxes(
+                    &mut fetch_session,
+                    &mailboxes,
+                    &state.checkpoints,
+                )
+                .await?
+            }
+        };
+    }
+}
+
+// How new mail is noticed - IDLE where the server supports it, otherwise polling
+enum Watch {
+    Idle(IdleHandle<ImapStream>),
+    Poll(ImapSession<ImapStream>),
+}
+
+// Wait until the server reports new mail in the mailboxes or idle_timeout passes
+async fn wait_idle(
+    idle_handle: &mut IdleHandle<ImapStream>,
+    config: &ImapConfig,
+    mailboxes: &[String],
+) -> Result<()> {
+    let before = SystemTime::now();
+    let mut cur_idle_msgs = 0;
 
     loop {
-        let _fetch_inbox = fetch_session.select(&config.mailbox).await?;
+        cur_idle_msgs += 1;
+
+        // Why is the server spamming so many non-interesting idle responses ?
+        if cur_idle_msgs > 100 {
+            error!("Idle response loop > 100 ?");
+            return Ok(());
+        }
 
-        let search = fetch_session.uid_search(config.search.clone()).await?;
+        // We would like to awake ourselves despite idle messages flooding in
+        let idle_left_secs = crate::imap_util::calculate_idle_left(before, config.idle_timeout);
 
-        let mut to_fetch = vec![];
+        let (idle_fut, _ss) = idle_handle.wait_with_timeout(Duration::from_secs(idle_left_secs));
+
+        let idle_res = idle_fut
+            .await
+            .map_err(|source| ImapError::Idle { source })?;
+
+        // If the idle response involves the maiblox, let's break and fetch new messages to check.
+        if crate::imap_util::is_idle_response_interesting(&idle_res, mailboxes) {
+            return Ok(());
+        }
+    }
+}
+
+// Produce records for the messages in the mailbox past its checkpoint
+// and carry out the actions of those acknowledged by the producer
+async fn sync_mailbox(
+    ctx: &SyncContext<'_>,
+    fetch_session: &mut ImapSession<ImapStream>,
+    state: &mut SessionState,
+    mailbox: &str,
+) -> Result<()> {
+    let config = ctx.config;
+    let sync_ext = ctx.sync_ext;
+    let checkpoints = &mut state.checkpoints;
+
+    let fetch_inbox = match sync_ext.condstore {
+        true => fetch_session.select_condstore(mailbox).await,
+        false => fetch_session.select(mailbox).await,
+    }
+    .map_err(|source| ImapError::Select {
+        mailbox: mailbox.to_string(),
+        source,
+    })?;
+
+    // Only look past the last UID we produced - UIDVALIDITY change resets us to 1
+    let next_uid = checkpoints.validate(mailbox, fetch_inbox.uid_validity)?;
+    let last_modseq = match sync_ext.condstore {
+        true => checkpoints.get(mailbox).and_then(|c| c.highest_modseq),
+        false => None,
+    };
+
+    let mut to_fetch = vec![];
+    if last_modseq.is_some() && last_modseq == fetch_inbox.highest_modseq {
+        debug!(
+            "HIGHESTMODSEQ {:?} of {} unchanged - nothing to search",
+            &last_modseq, mailbox
+        );
+    } else {
+        // No MODSEQ criterion - async-imap fails to parse the (MODSEQ n) it adds to the SEARCH response,
+        // and UIDs past the checkpoint are newer than its HIGHESTMODSEQ anyway
+        let search_query = format!("UID {}:* {}", next_uid, config.search);
+
+        let search = fetch_session
+            .uid_search(&search_query)
+            .await
+            .map_err(|source| ImapError::Search {
+                mailbox: mailbox.to_string(),
+                source,
+            })?;
+
+        // n:* always matches the highest UID even when it is below n
         for search_item in &search {
-            to_fetch.push(*search_item);
+            if *search_item >= next_uid {
+                to_fetch.push(*search_item);
+            }
         }
         drop(search);
-
-        let mut uid_moves: Vec<(String, String)> = vec![];
+        to_fetch.sort_unstable();
 
         debug!(
-            "Checking {} found {} emails to fetch",
-            &config.search,
+            "Checking {} on {} found {} emails to fetch",
+            &search_query,
+            mailbox,
             to_fetch.len()
         );
+    }
 
-        for fetch_uid in &to_fetch {
-            let uid = fetch_uid.to_string();
+    // Gmail ids & labels are not exposed by async-imap - fetched apart and merged into the records
+    let gmail = sync_ext.gmail && crate::imap_util::wants_gmail_attributes(&config.fetch);
+    let fetch = match gmail {
+        true => crate::imap_util::without_gmail_attributes(&config.fetch),
+        false => config.fetch.clone(),
+    };
+    let fetch_query = match last_modseq {
+        Some(modseq) if sync_ext.qresync => {
+            format!("{} (CHANGEDSINCE {} VANISHED)", fetch, modseq)
+        }
+        Some(modseq) => format!("{} (CHANGEDSINCE {})", fetch, modseq),
+        None if sync_ext.condstore => crate::imap_util::with_modseq(&fetch),
+        None => fetch,
+    };
+
+    // One UID FETCH per batch - the messages are processed as they stream in
+    for batch in to_fetch.chunks(config.fetch_batch_size) {
+        let uid_set = crate::imap_util::uid_set(batch);
+
+        let fetch_error = |source| ImapError::Fetch {
+            mailbox: mailbox.to_string(),
+            uids: uid_set.clone(),
+            source,
+        };
 
-            debug!("Fetching UID {:?}", &uid);
-            let mut fetch_new = fetch_session.uid_fetch(&uid, config.fetch.clone()).await?;
+        let gmail_attributes = match gmail {
+            true => crate::imap_util::gmail_attributes(fetch_session, &uid_set)
+                .await
+                .map_err(fetch_error)?,
+            false => HashMap::new(),
+        };
 
-            while let Some(item_u) = fetch_new.next().await {
-                let item = &item_u.unwrap();
-                let rec = crate::record::fill_record(&config, uid.clone(), item, do_dkim_auth)?;
+        debug!("Fetching UIDs {}", &uid_set);
+        let mut fetch_new = fetch_session
+            .uid_fetch(&uid_set, &fetch_query)
+            .await
+            .map_err(fetch_error)?;
+        let mut fetched = HashSet::new();
+
+        while let Some(item_u) = fetch_new.next().await {
+            let item = &item_u.map_err(fetch_error)?;
+
+            // Unsolicited FETCHes e.g. of flag changes carry other UIDs or none at all
+            let fetch_uid = match item.uid {
+                Some(fetch_uid) if batch.binary_search(&fetch_uid).is_ok() => fetch_uid,
+                _ => {
+                    debug!("Ignoring FETCH outside of the batch {:?}", item.uid);
+                    continue;
+                }
+            };
+            if !fetched.insert(fetch_uid) {
+                continue;
+            }
+            let uid = fetch_uid.to_string();
+            if gmail && !gmail_attributes.contains_key(&fetch_uid) {
+                warn!("No Gmail attributes for UID {} of {}", &uid, mailbox);
+            }
 
-                // Move the mail between Mailboxes
-                if let Some(ref move_to) = rec.moved_to {
-                    info!("Moving {} to {}", &uid, &move_to);
-                    uid_moves.push((uid.clone(), move_to.clone()));
+            let processed = match process_message(
+                ctx,
+                mailbox,
+                &uid,
+                item,
+                gmail_attributes.get(&fetch_uid),
+                fetch_inbox.uid_validity,
+            )
+            .await
+            {
+                Ok(processed) => processed,
+                // A single bad message must not stop the connector
+                Err(e) => {
+                    warn!("Failed to process UID {} of {}: {:?}", &uid, mailbox, e);
+                    failed_message(config, mailbox, &uid, &e)?
                 }
+            };
+            if !processed.actions.is_empty() {
+                debug!("Will carry out {:?} on {}", &processed.actions, &uid);
+            }
+
+            if processed.skip_produce {
+                debug!("Rules skip producing UID {}", &uid);
+                state.acks.skipped(mailbox, fetch_uid, processed.actions);
+                state.checkpoints.advance(mailbox, fetch_uid);
+                continue;
+            }
 
-                tx.send(rec.try_into()?).await?;
+            let records = 1 + processed.children.len();
+            let values = std::iter::once(processed.value).chain(processed.children);
+            for value in values {
+                ctx.tx
+                    .send(ImapRecord {
+                        mailbox: mailbox.to_string(),
+                        uid: fetch_uid,
+                        key: processed.key.clone(),
+                        value,
+                        error: processed.error,
+                        ack: state.acks.sender(),
+                    })
+                    .await
+                    .map_err(|e| ImapError::Produce {
+                        reason: e.to_string(),
+                    })?;
             }
+            state
+                .acks
+                .produced(mailbox, fetch_uid, records, processed.actions);
         }
+        drop(fetch_new);
 
-        for (move_uid, move_to) in uid_moves.iter() {
-            fetch_session.uid_mv(move_uid, move_to).await?;
+        // Nothing to wait for e.g. when the message vanished in the meantime
+        for fetch_uid in batch.iter().filter(|uid| !fetched.contains(uid)) {
+            state.checkpoints.advance(mailbox, *fetch_uid);
         }
+    }
+
+    // e.g. VANISHED for messages expunged in between the search and fetch
+    while let Ok(unsolicited) = fetch_session.unsolicited_responses.try_recv() {
+        debug!("Unsolicited response {:?}", unsolicited);
+    }
 
-        let before = SystemTime::now();
-        let mut cur_idle_msgs = 0;
+    state.acks.wait_for(mailbox, &mut state.checkpoints).await?;
 
-        loop {
-            cur_idle_msgs += 1;
+    if sync_ext.condstore {
+        state
+            .checkpoints
+            .set_modseq(mailbox, fetch_inbox.highest_modseq);
+    }
+    state.checkpoints.persist()?;
 
-            // Why is the server spamming so many non-interesting idle responses ?
-            if cur_idle_msgs > 100 {
-                error!("Idle response loop > 100 ?");
-                break;
+    let acked = state.acks.acked(mailbox);
+    crate::actions::apply_actions(fetch_session, mailbox, acked).await?;
+    state.acks.actions_done(mailbox);
+
+    Ok(())
+}
+
+// A fetched message turned into its record
+struct ProcessedMessage {
+    key: Option<String>,
+    value: String,
+    // Further records e.g. attachments - keyed the same as the message
+    children: Vec<String>,
+    // Carried out once the record is acknowledged
+    actions: Vec<MessageAction>,
+    skip_produce: bool,
+    error: bool,
+}
+
+async fn process_message(
+    ctx: &SyncContext<'_>,
+    mailbox: &str,
+    uid: &str,
+    item: &Fetch,
+    gmail: Option<&GmailAttributes>,
+    uid_validity: Option<u32>,
+) -> Result<ProcessedMessage> {
+    let config = ctx.config;
+
+    let dkim_verification = match (ctx.dkim_resolver, item.body()) {
+        (Some(resolver), Some(raw)) => Some(crate::dkim::verify_message(resolver, raw).await),
+        _ => None,
+    };
+
+    let mut rec = crate::record::fill_record(
+        config,
+        mailbox,
+        uid.to_string(),
+        item,
+        ctx.do_dkim_auth,
+        dkim_verification,
+    )?;
+    if let Some(gmail) = gmail {
+        rec.gmail_msgid = gmail.msgid;
+        rec.gmail_thrid = gmail.thrid;
+        rec.gmail_labels = gmail.labels.clone();
+    }
+
+    let mut children = vec![];
+    if let (Some(attachments), Some(raw)) = (&config.attachments, item.body()) {
+        let (metas, attachment_records) =
+            crate::attachment::extract(attachments, mailbox, uid, raw)?;
+        rec.attachments = Some(metas);
+        children = attachment_records;
+    }
+
+    let outcome = ctx.rules.evaluate(&rec, item);
+    if !outcome.matched.is_empty() {
+        rec.matched_rules = Some(outcome.matched);
+    }
+    if !outcome.tags.is_empty() {
+        rec.tags = Some(outcome.tags);
+    }
+
+    // Move / flag etc. the mail once the record is acknowledged
+    let mut actions = crate::actions::resolve_actions(config, &rec);
+    actions.extend(outcome.actions);
+
+    let key = config
+        .record_key
+        .as_ref()
+        .and_then(|k| crate::record::record_key(k, &rec, item, uid_validity));
+
+    Ok(ProcessedMessage {
+        key,
+        value: rec.try_into()?,
+        children,
+        actions,
+        skip_produce: outcome.skip_produce,
+        error: false,
+    })
+}
+
+// Error record in place of the message - to the dead letter topic or inline - optionally quarantining the message
+fn failed_message(
+    config: &ImapConfig,
+    mailbox: &str,
+    uid: &str,
+    err: &anyhow::Error,
+) -> Result<ProcessedMessage> {
+    let mut rec = ImapEvent::new(mailbox, uid.to_string());
+    rec.error = Some(format!("{:#}", err));
+
+    let mut actions = vec![];
+    if let Some(quarantine) = &config.quarantine_mailbox {
+        rec.moved_to = Some(quarantine.clone());
+        actions.push(MessageAction::Move(quarantine.clone()));
+    }
+
+    Ok(ProcessedMessage {
+        key: None,
+        value: rec.try_into()?,
+        children: vec![],
+        actions,
+        skip_produce: false,
+        error: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::MailboxCheckpoint;
+    use crate::event::RecordAck;
+    use crate::mock_imap::{config, Failure, MockImapServer};
+    use async_std::channel::Receiver;
+    use async_std::future::timeout;
+    use futures::future::{select, Either};
+    use std::future::Future;
+
+    const MESSAGE: &[u8] = b"Return-Path: <alice@example.com>\r\n\
+        From: Alice <alice@example.com>\r\n\
+        To: bob@example.org\r\n\
+        Subject: Hello\r\n\
+        Message-ID: <1@example.com>\r\n\
+        \r\n\
+        Hi Bob\r\n";
+
+    fn session_state(config: &ImapConfig) -> SessionState {
+        SessionState {
+            checkpoints: Checkpoints::load(config.checkpoint_file.as_deref()).unwrap(),
+            tokens: TokenProvider::new(config.auth.clone()),
+            acks: AckTracker::new(),
+            established: false,
+        }
+    }
+
+    // Runs the IMAP loop until the test body finishes - or fails with the error the loop stopped with
+    async fn drive<F, T>(
+        config: &ImapConfig,
+        body: impl FnOnce(Receiver<ImapRecord>) -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = T>,
+    {
+        drive_session(config, &mut session_state(config), body).await
+    }
+
+    // As drive, the state carried over from a previous session the way the supervisor does upon reconnect
+    async fn drive_session<F, T>(
+        config: &ImapConfig,
+        state: &mut SessionState,
+        body: impl FnOnce(Receiver<ImapRecord>) -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = T>,
+    {
+        let (tx, rx) = channel::bounded(CHANNEL_BUFFER_SIZE);
+        let rules = Rules::new(&config.rules)?;
+
+        let run = Box::pin(imap_loop(&tx, config, &rules, None, state));
+        let body = Box::pin(body(rx));
+        match timeout(Duration::from_secs(30), select(run, body)).await? {
+            Either::Left((res, _)) => {
+                res?;
+                bail!("IMAP loop finished")
             }
+            Either::Right((value, _)) => Ok(value),
+        }
+    }
 
-            // We would like to awake ourselves despite idle messages flooding in
-            let idle_left_secs = crate::imap_util::calculate_idle_left(before, config.idle_timeout);
+    // Acknowledge the next record the way the producer does and hand back its JSON
+    async fn next_record(rx: &Receiver<ImapRecord>) -> serde_json::Value {
+        acknowledge(rx.recv().await.unwrap()).await
+    }
+
+    async fn acknowledge(record: ImapRecord) -> serde_json::Value {
+        record
+            .ack
+            .send(RecordAck {
+                mailbox: record.mailbox.clone(),
+                uid: record.uid,
+            })
+            .await
+            .unwrap();
+        serde_json::from_str(&record.value).unwrap()
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        while !condition() {
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        }
+    }
 
-            let (idle_fut, _ss) =
-                idle_handle.wait_with_timeout(std::time::Duration::from_secs(idle_left_secs));
+    // A checkpoint file of its own per test as the tests run in parallel
+    fn checkpoint_file(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("imap-source-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
 
-            let idle_res = idle_fut.await?;
+    fn checkpoint(path: &str, mailbox: &str) -> Option<MailboxCheckpoint> {
+        Checkpoints::load(Some(path)).unwrap().get(mailbox).cloned()
+    }
 
-            // If the idle response involves the maiblox, let's break and fetch new messages to check.
-            if crate::imap_util::is_idle_response_interesting(&idle_res, &config.mailbox) {
-                break;
+    // Commands the server received from the given index on
+    fn commands_since(server: &MockImapServer, start: usize) -> Vec<String> {
+        server.commands().into_iter().skip(start).collect()
+    }
+
+    #[async_std::test]
+    async fn produces_and_moves_upon_idle_wakeup() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"on_produced": [{"action": "move", "mailbox": "Archive"}]}),
+        );
+
+        let server = &server;
+        let records = drive(&config, |rx| async move {
+            let first = next_record(&rx).await;
+
+            // New mail only wakes up the idling session
+            wait_until(|| server.commands().iter().any(|c| c == "IDLE")).await;
+            server.append("INBOX", MESSAGE).await;
+            let second = next_record(&rx).await;
+
+            wait_until(|| server.messages("Archive").len() == 2).await;
+            vec![first, second]
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(records[0]["mailbox"], "INBOX");
+        assert_eq!(records[0]["uid"], "1");
+        assert_eq!(records[0]["flags"], serde_json::json!([]));
+        assert_eq!(records[0]["size"], MESSAGE.len());
+        assert!(records[0]["body_utf8_lossy"]
+            .as_str()
+            .unwrap()
+            .contains("Hi Bob"));
+        assert_eq!(records[1]["uid"], "2");
+
+        assert!(server.messages("INBOX").is_empty());
+        let commands = server.commands();
+        assert!(commands.iter().any(|c| c.starts_with("CREATE")));
+        assert!(commands.iter().any(|c| c.starts_with("UID MOVE 1 ")));
+        assert!(commands.iter().any(|c| c.starts_with("UID MOVE 2 ")));
+    }
+
+    #[async_std::test]
+    async fn retries_failed_move_after_reconnect() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        server.fail_next("UID MOVE", Failure::Disconnect);
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"on_produced": [{"action": "move", "mailbox": "Archive"}]}),
+        );
+
+        let mut state = session_state(&config);
+        let res = drive_session(&config, &mut state, |rx| async move {
+            next_record(&rx).await;
+            std::future::pending::<()>().await
+        })
+        .await;
+        assert!(res.is_err(), "The move must fail: {:?}", res);
+        assert_eq!(server.messages("INBOX").len(), 1);
+
+        // The acknowledged message is moved by the next session without being produced again
+        let server = &server;
+        let rx = drive_session(&config, &mut state, |rx| async move {
+            wait_until(|| server.messages("Archive").len() == 1).await;
+            rx
+        })
+        .await
+        .unwrap();
+        assert!(server.messages("INBOX").is_empty());
+        assert!(rx.try_recv().is_err());
+        let moves = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID MOVE 1 "))
+            .count();
+        assert_eq!(moves, 2);
+    }
+
+    #[async_std::test]
+    async fn produces_over_self_signed_tls() {
+        let acceptor = MockImapServer::self_signed_acceptor();
+        let server = MockImapServer::start("user", "secret", Some(acceptor)).await;
+        server.append("INBOX", MESSAGE).await;
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            true,
+            serde_json::json!({"fetch_batch_size": 1, "mode_normalized": true}),
+        );
+
+        let records = drive(&config, |rx| async move {
+            vec![next_record(&rx).await, next_record(&rx).await]
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(records[0]["uid"], "1");
+        assert_eq!(records[1]["uid"], "2");
+        assert_eq!(records[1]["normalized"]["subject"], "Hello");
+        assert_eq!(
+            records[1]["normalized"]["from"][0]["email"],
+            "alice@example.com"
+        );
+        let fetches: Vec<String> = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID FETCH"))
+            .collect();
+        assert_eq!(fetches.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn resumes_from_checkpoint_after_dropped_connection() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"reconnect": {"initial_delay_ms": 10, "max_delay_ms": 50}}),
+        );
+
+        // Through the supervisor the way the connector runs it
+        let mut records = ImapSource::new(config)
+            .unwrap()
+            .connect(None)
+            .await
+            .unwrap();
+        let mut next = || timeout(Duration::from_secs(30), records.next());
+        let first = acknowledge(next().await.unwrap().unwrap()).await;
+        assert_eq!(first["uid"], "1");
+
+        timeout(
+            Duration::from_secs(30),
+            wait_until(|| server.commands().iter().any(|c| c == "IDLE")),
+        )
+        .await
+        .unwrap();
+        server.fail_next("UID SEARCH", Failure::Disconnect);
+        server.append("INBOX", MESSAGE).await;
+
+        let second = acknowledge(next().await.unwrap().unwrap()).await;
+        assert_eq!(second["uid"], "2");
+        // Nothing produced twice
+        assert!(timeout(Duration::from_millis(500), records.next())
+            .await
+            .is_err());
+
+        let commands = server.commands();
+        let count = |command: &str| commands.iter().filter(|c| *c == command).count();
+        assert_eq!(
+            commands.iter().filter(|c| c.starts_with("LOGIN")).count(),
+            4
+        );
+        assert_eq!(count("UID SEARCH UID 1:* ALL"), 1);
+        // The one the connection dropped upon and the one of the new session
+        assert_eq!(count("UID SEARCH UID 2:* ALL"), 2);
+        assert_eq!(count("UID FETCH 1 (UID FLAGS RFC822.SIZE RFC822)"), 1);
+    }
+
+    #[test]
+    fn rejects_non_finite_backoff() {
+        let mut config = config(993, true, serde_json::json!({}));
+        config.reconnect.jitter = f64::NAN;
+        assert!(ImapSource::new(config).is_err());
+    }
+
+    #[test]
+    fn rejects_mailbox_topic_collision() {
+        let config = config(
+            993,
+            true,
+            serde_json::json!({"mailbox": ["Projects/A", "Projects-A"], "mailbox_topic": "mail-{mailbox}"}),
+        );
+        let err = ImapSource::new(config).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImapError>(),
+            Some(ImapError::MailboxTopic { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_auth_result_rule_without_mode_auth_results() {
+        let rules = serde_json::json!([{
+            "name": "dmarc-fail",
+            "conditions": [{"auth_result": {"method": "dmarc", "result": "fail"}}],
+            "actions": [{"move": "Spam"}],
+        }]);
+        let config = |mode_auth_results| {
+            config(
+                993,
+                true,
+                serde_json::json!({"rules": rules.clone(), "mode_auth_results": mode_auth_results}),
+            )
+        };
+        let err = ImapSource::new(config(false)).unwrap_err();
+        assert!(err.to_string().contains("dmarc-fail"));
+        assert!(ImapSource::new(config(true)).is_ok());
+    }
+
+    #[async_std::test]
+    async fn polls_without_idle() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 UIDPLUS MOVE");
+        server.append("INBOX", MESSAGE).await;
+        let config = config(server.port, false, serde_json::json!({"poll_interval": 1}));
+
+        let server = &server;
+        let records = drive(&config, |rx| async move {
+            let first = next_record(&rx).await;
+            server.append("INBOX", MESSAGE).await;
+            vec![first, next_record(&rx).await]
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(records[0]["uid"], "1");
+        assert_eq!(records[1]["uid"], "2");
+        let commands = server.commands();
+        assert!(commands.iter().all(|c| c != "IDLE"));
+        assert!(commands.iter().any(|c| c == "NOOP"));
+    }
+
+    #[async_std::test]
+    async fn merges_gmail_attributes() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE UIDPLUS MOVE X-GM-EXT-1");
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"fetch": "(UID FLAGS X-GM-MSGID X-GM-THRID X-GM-LABELS RFC822)"}),
+        );
+
+        let record = drive(&config, |rx| async move { next_record(&rx).await })
+            .await
+            .unwrap();
+        assert_eq!(record["gmail_msgid"], 1001);
+        assert_eq!(record["gmail_thrid"], 2001);
+        assert_eq!(
+            record["gmail_labels"],
+            serde_json::json!(["\\Inbox", "Mock label"])
+        );
+
+        // Fetched apart - async-imap would drop them from the message FETCH
+        let fetches: Vec<String> = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID FETCH"))
+            .collect();
+        assert_eq!(
+            fetches,
+            [
+                "UID FETCH 1 (UID X-GM-MSGID X-GM-THRID X-GM-LABELS)",
+                "UID FETCH 1 (UID FLAGS RFC822)"
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn untrusted_certificate_is_fatal() {
+        let acceptor = MockImapServer::self_signed_acceptor();
+        let server = MockImapServer::start("user", "secret", Some(acceptor)).await;
+        let config = config(
+            server.port,
+            true,
+            serde_json::json!({"dangerous_cert": false}),
+        );
+
+        let res = drive(&config, |rx| async move { next_record(&rx).await }).await;
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Tls { .. }) => assert!(!err.is_retryable()),
+            _ => panic!("Expected a TLS failure: {:?}", err),
+        }
+        assert!(server.commands().is_empty());
+    }
+
+    #[async_std::test]
+    async fn delete_requires_uidplus() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE MOVE");
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"on_produced": [{"action": "delete"}]}),
+        );
+
+        let res = drive(&config, |rx| async move { next_record(&rx).await }).await;
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Capability { .. }) => assert!(!err.is_retryable()),
+            _ => panic!("Expected a missing capability: {:?}", err),
+        }
+        assert!(server.commands().iter().all(|c| !c.starts_with("UID")));
+    }
+
+    #[async_std::test]
+    async fn resolved_mailbox_topic_collision() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        server.append("Projects/A", MESSAGE).await;
+        server.append("Projects-A", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"mailbox": ["INBOX", "Projects*"], "mailbox_topic": "mail-{mailbox}"}),
+        );
+        assert!(ImapSource::new(config.clone()).is_ok());
+
+        let res = drive(&config, |rx| async move { next_record(&rx).await }).await;
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::MailboxTopic { topic, .. }) => {
+                assert_eq!(topic, "mail-projects-a");
+                assert!(!err.is_retryable());
             }
+            _ => panic!("Expected a mailbox topic collision: {:?}", err),
+        }
+    }
+
+    #[async_std::test]
+    async fn error_record_then_disconnect() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        // No Return-Path - the DKIM check of mode_dkim_auth fails the message
+        server
+            .append(
+                "INBOX",
+                b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHi\r\n",
+            )
+            .await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"fetch": "(UID RFC822.HEADER)", "mode_dkim_auth": true}),
+        );
+
+        let mut error_record = None;
+        let (server, first) = (&server, &mut error_record);
+        let res = drive(&config, |rx| async move {
+            *first = Some(next_record(&rx).await);
+
+            wait_until(|| server.commands().iter().any(|c| c == "IDLE")).await;
+            server.fail_next("UID SEARCH", Failure::Disconnect);
+            server.append("INBOX", MESSAGE).await;
+            next_record(&rx).await
+        })
+        .await;
+
+        let error_record = error_record.unwrap();
+        assert_eq!(error_record["uid"], "1");
+        assert!(error_record["error"].as_str().is_some());
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Search { .. }) => assert!(err.is_retryable()),
+            _ => panic!("Expected a search failure: {:?}", err),
+        }
+    }
+
+    #[async_std::test]
+    async fn failed_fetch_stops_session() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        server.fail_next("UID FETCH", Failure::No);
+        let config = config(server.port, false, serde_json::json!({}));
+
+        let res = drive(&config, |rx| async move { next_record(&rx).await }).await;
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Fetch { .. }) => assert!(err.is_retryable()),
+            _ => panic!("Expected a fetch failure: {:?}", err),
+        }
+        assert_eq!(server.messages("INBOX").len(), 1);
+    }
+
+    #[async_std::test]
+    async fn wrong_password_fails_login() {
+        let server = MockImapServer::start("user", "other", None).await;
+        let config = config(server.port, false, serde_json::json!({}));
+
+        let res = drive(&config, |rx| async move { next_record(&rx).await }).await;
+        let err = res.unwrap_err();
+        match err.downcast_ref::<ImapError>() {
+            Some(err @ ImapError::Auth { .. }) => assert!(!err.is_retryable()),
+            _ => panic!("Expected an authentication failure: {:?}", err),
         }
+        assert!(server.commands().iter().all(|c| !c.starts_with("SELECT")));
+    }
+
+    #[async_std::test]
+    async fn qresync_resumes_from_highest_modseq() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE UIDPLUS MOVE CONDSTORE QRESYNC");
+        server.append("INBOX", MESSAGE).await;
+        let path = checkpoint_file("qresync");
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"checkpoint_file": path}),
+        );
+
+        let (server, path) = (&server, path.as_str());
+        let first = drive(&config, |rx| async move {
+            let record = next_record(&rx).await;
+            wait_until(|| checkpoint(path, "INBOX").and_then(|c| c.highest_modseq) == Some(2))
+                .await;
+            record
+        })
+        .await
+        .unwrap();
+        assert_eq!(first["uid"], "1");
+        assert_eq!(first["modseq"], 2);
+        let commands = server.commands();
+        assert!(commands.iter().any(|c| c == "ENABLE QRESYNC"));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("SELECT") && c.ends_with("(CONDSTORE)")));
+        assert!(commands
+            .iter()
+            .any(|c| c == "UID FETCH 1 (UID FLAGS RFC822.SIZE RFC822 MODSEQ)"));
+
+        // A restart picks up the persisted HIGHESTMODSEQ
+        server.append("INBOX", MESSAGE).await;
+        let start = server.commands().len();
+        let second = drive(&config, |rx| async move {
+            let record = next_record(&rx).await;
+            wait_until(|| checkpoint(path, "INBOX").and_then(|c| c.highest_modseq) == Some(3))
+                .await;
+            record
+        })
+        .await
+        .unwrap();
+        assert_eq!(second["uid"], "2");
+        assert_eq!(second["modseq"], 3);
+        let commands = commands_since(server, start);
+        assert!(commands.iter().any(|c| c == "UID SEARCH UID 2:* ALL"));
+        assert!(commands.iter().any(|c| {
+            c == "UID FETCH 2 (UID FLAGS RFC822.SIZE RFC822) (CHANGEDSINCE 2 VANISHED)"
+        }));
+        assert_eq!(
+            checkpoint(path, "INBOX"),
+            Some(MailboxCheckpoint {
+                uid_validity: 1,
+                last_uid: 2,
+                highest_modseq: Some(3),
+            })
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[async_std::test]
+    async fn condstore_skips_unchanged_mailbox() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE UIDPLUS MOVE CONDSTORE");
+        server.append("INBOX", MESSAGE).await;
+        let path = checkpoint_file("condstore");
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"checkpoint_file": path}),
+        );
+
+        let (server, path) = (&server, path.as_str());
+        drive(&config, |rx| async move {
+            next_record(&rx).await;
+            wait_until(|| checkpoint(path, "INBOX").and_then(|c| c.highest_modseq) == Some(2))
+                .await;
+        })
+        .await
+        .unwrap();
+
+        // Nothing changed in between - the restart goes straight to IDLE without a search
+        let start = server.commands().len();
+        let record = drive(&config, |rx| async move {
+            wait_until(|| commands_since(server, start).iter().any(|c| c == "IDLE")).await;
+            assert!(commands_since(server, start)
+                .iter()
+                .all(|c| !c.starts_with("UID SEARCH")));
+
+            // New mail moves HIGHESTMODSEQ
+            server.append("INBOX", MESSAGE).await;
+            next_record(&rx).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(record["uid"], "2");
+        let commands = commands_since(server, start);
+        assert!(commands.iter().all(|c| !c.starts_with("ENABLE")));
+        assert!(commands.iter().any(|c| c == "UID SEARCH UID 2:* ALL"));
+        assert!(commands
+            .iter()
+            .any(|c| c == "UID FETCH 2 (UID FLAGS RFC822.SIZE RFC822) (CHANGEDSINCE 2)"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[async_std::test]
+    async fn full_search_without_condstore() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.append("INBOX", MESSAGE).await;
+        let path = checkpoint_file("no-condstore");
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"checkpoint_file": path}),
+        );
+
+        let (server, path) = (&server, path.as_str());
+        let record = drive(&config, |rx| async move {
+            let record = next_record(&rx).await;
+            wait_until(|| checkpoint(path, "INBOX").is_some_and(|c| c.last_uid == 1)).await;
+            record
+        })
+        .await
+        .unwrap();
+        assert_eq!(record["uid"], "1");
+        assert_eq!(record["modseq"], serde_json::Value::Null);
+
+        let commands = server.commands();
+        assert!(commands.iter().all(|c| !c.starts_with("ENABLE")));
+        assert!(commands.iter().all(|c| !c.contains("CONDSTORE")));
+        assert!(commands.iter().any(|c| c == "UID SEARCH UID 1:* ALL"));
+        assert!(commands
+            .iter()
+            .any(|c| c == "UID FETCH 1 (UID FLAGS RFC822.SIZE RFC822)"));
+        assert_eq!(checkpoint(path, "INBOX").unwrap().highest_modseq, None);
+        let _ = std::fs::remove_file(path);
+    }
+
+    // New mail in the mailbox other than the one the idle session selected
+    async fn produce_from_other_mailbox(server: &MockImapServer) -> Vec<serde_json::Value> {
+        server.append("INBOX", MESSAGE).await;
+        server.append("Other", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({"mailbox": ["INBOX", "Other"], "poll_interval": 1}),
+        );
+
+        drive(&config, |rx| async move {
+            let mut records = vec![next_record(&rx).await, next_record(&rx).await];
+            wait_until(|| server.commands().iter().any(|c| c == "IDLE" || c == "NOOP")).await;
+            server.append("Other", MESSAGE).await;
+            records.push(next_record(&rx).await);
+            records
+        })
+        .await
+        .unwrap()
+    }
+
+    #[async_std::test]
+    async fn notify_wakes_up_for_other_mailboxes() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE UIDPLUS MOVE NOTIFY");
+
+        let records = produce_from_other_mailbox(&server).await;
+        assert_eq!(records[2]["mailbox"], "Other");
+        assert_eq!(records[2]["uid"], "2");
+        let commands = server.commands();
+        assert!(commands.iter().any(|c| c.starts_with("NOTIFY SET")));
+        assert!(commands.iter().any(|c| c == "IDLE"));
+        assert!(commands.iter().all(|c| c != "NOOP"));
+    }
+
+    #[async_std::test]
+    async fn polls_when_notify_rejected() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        server.set_capabilities("IMAP4rev1 IDLE UIDPLUS MOVE NOTIFY");
+        server.fail_next("NOTIFY", Failure::No);
+
+        let records = produce_from_other_mailbox(&server).await;
+        assert_eq!(records[2]["mailbox"], "Other");
+        assert_eq!(records[2]["uid"], "2");
+        let commands = server.commands();
+        assert!(commands.iter().all(|c| c != "IDLE"));
+        assert!(commands.iter().any(|c| c == "NOOP"));
+    }
+
+    #[async_std::test]
+    async fn polls_without_notify() {
+        let server = MockImapServer::start("user", "secret", None).await;
+
+        let records = produce_from_other_mailbox(&server).await;
+        assert_eq!(records[2]["mailbox"], "Other");
+        assert_eq!(records[2]["uid"], "2");
+        let commands = server.commands();
+        assert!(commands.iter().all(|c| !c.starts_with("NOTIFY")));
+        assert!(commands.iter().all(|c| c != "IDLE"));
+        assert!(commands.iter().any(|c| c.starts_with("STATUS")));
+    }
+
+    #[async_std::test]
+    async fn failed_message_to_dead_letter_and_quarantine() {
+        let server = MockImapServer::start("user", "secret", None).await;
+        // No Return-Path - the DKIM check of mode_dkim_auth fails the message
+        server
+            .append(
+                "INBOX",
+                b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHi\r\n",
+            )
+            .await;
+        server.append("INBOX", MESSAGE).await;
+        let config = config(
+            server.port,
+            false,
+            serde_json::json!({
+                "fetch": "(UID RFC822.HEADER)",
+                "mode_dkim_auth": true,
+                "dead_letter_topic": "mail-errors",
+                "quarantine_mailbox": "Quarantine"
+            }),
+        );
+        let topic_producers = crate::producer::TopicProducers::new(&config).unwrap();
+
+        let server = &server;
+        let records = drive(&config, |rx| async move {
+            let mut records = vec![];
+            for _ in 0..2 {
+                let record = rx.recv().await.unwrap();
+                let topic = topic_producers.topic(&record.mailbox, record.error);
+                records.push((record.uid, record.error, topic));
+                record
+                    .ack
+                    .send(RecordAck {
+                        mailbox: record.mailbox.clone(),
+                        uid: record.uid,
+                    })
+                    .await
+                    .unwrap();
+            }
+            wait_until(|| server.messages("Quarantine").len() == 1).await;
+            records
+        })
+        .await
+        .unwrap();
+
+        // The next message still goes to the connector topic
+        assert_eq!(
+            records,
+            [(1, true, Some("mail-errors".to_string())), (2, false, None)]
+        );
+        let inbox = server.messages("INBOX");
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(inbox[0].raw, MESSAGE);
+        assert!(server.messages("Quarantine")[0]
+            .raw
+            .starts_with(b"From: alice@example.com"));
     }
 }
@@ -0,0 +1,4 @@
+// Lowercase hex as used for digests & pins
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}